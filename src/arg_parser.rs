@@ -1,32 +1,282 @@
 use docopt;
 
-use std::path::PathBuf;
+use std::io;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::error::Error as StdError;
 
-use error::Error as SkarnError;
-use config::{Config, DeleteBehaviour};
-use config::PatternSource::IncludeFile;
-use compare::{ComparisonMethod, Content};
+use skarn::Error as SkarnError;
+use skarn::config::{Config, DeleteBehaviour, SymlinkMode, Preserve, OutputFormat,
+                    ConflictPolicy};
+use skarn::config::PatternSource::{IncludeFile, Git};
+use skarn::compare::{ComparisonMethod, Content, Metadata, Hash};
+use skarn::hash::HashCache;
+use skarn::transform::Transform;
+use skarn::vfs::{FileSystem, RealFs};
+use skarn::backup::Backup;
+use skarn::snapshot::Snapshot;
+use skarn::dated::Retention;
+
+use time::Duration;
 
 docopt! { Args, "
-Usage: skarn --include <include-file> [options] <source> <dest>
+Usage: skarn (--include <include-file> | --git) [--transform <rule>]... [options] <source> <dest>
+       skarn watch (--include <include-file> | --git) [--transform <rule>]... [options] <source> <dest>
+       skarn bisync (--include <include-file> | --git) --state <state-file> [options] <source> <dest>
+       skarn explain (--include <include-file> | --git) [options] <source> <path>
 
 --delete <delete-behaviour>
-"
+--dry-run
+--format <format>  How to print the plan for --dry-run: itemized, json or ndjson (itemized only for bisync) [default: itemized]
+--symlinks <mode>  How to treat symlinks: copy, rewrite or follow [default: copy]
+--compare <method>  How to compare files: metadata (implying --preserve times), content or hash [default: metadata]
+--hash-cache <file>  File to cache hashes in, for use with --compare hash
+--preserve <attributes>  Metadata to preserve: mode, owner, times or all
+--detect-moves  Rename files in the destination that were moved in the source, if --delete covers their old paths
+--transform <rule>  Produce matching files with a command: <pattern>:<extension>:<command>
+--mtime-tolerance <seconds>  Maximum difference in modification times [default: 0]
+--max-delete <count>  Abort if more than this many paths would be deleted
+--max-delete-percent <percent>  Abort if more than this percentage of the destination (or of either side, for bisync) would be deleted
+--expect-mount  Abort unless the source directory (or both directories, for bisync) is a mount point
+--force  Sync even if the deletion safety checks fail
+--backup-dir <dir>  Move deleted and overwritten files into a dated directory within <dir>
+--backup-keep <count>  Number of dated backup directories to keep
+--backup-max-age <days>  Remove dated backup directories older than this
+--snapshot  Sync into a new dated snapshot within <dest>, hard-linking unchanged files
+--snapshot-keep <count>  Number of snapshots to keep
+--snapshot-max-age <days>  Remove snapshots older than this
+--state <state-file>  File recording the state of each file after the last bidirectional sync
+--conflict <policy>  How bisync resolves files changed on both sides: newer, source, both or skip [default: skip]
+--threads <count>  Number of threads to traverse directories with [default: 4]
+--debounce <ms>  When watching, how long to wait for changes to stop before syncing [default: 500]
+",
+flag_mtime_tolerance: u64,
+flag_debounce: u32,
+flag_threads: usize
 }
 
 pub fn parse_args() -> Result<Config, SkarnError> {
-    let args: Args = try!(Args::docopt().decode());
+    let args: Args = try!(Args::docopt().decode().map_err(|e| {
+        SkarnError::new("docopt parsing error").with_cause(box e as Box<StdError>)
+    }));
 
-    let delete_behaviour = try!(DeleteBehaviour::from_str(&args.flag_delete[..]));
+    // Explaining a path's classification doesn't delete anything, so doesn't need --delete.
+    let delete_behaviour = if args.cmd_explain {
+        HashSet::new()
+    } else {
+        try!(DeleteBehaviour::from_str(&args.flag_delete[..]))
+    };
 
     debug!("delete behaviour set to: {:?}", delete_behaviour);
 
+    let symlink_mode = try!(SymlinkMode::from_str(&args.flag_symlinks[..]));
+
+    let comparison_method = match &args.flag_compare[..] {
+        "metadata" => box Metadata { mtime_tolerance: args.flag_mtime_tolerance } as Box<ComparisonMethod>,
+        "content" => box Content as Box<ComparisonMethod>,
+        "hash" => {
+            let cache_path = if args.flag_hash_cache.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(&args.flag_hash_cache[..]))
+            };
+            let cache = try!(HashCache::open(cache_path));
+            box Hash { cache: cache } as Box<ComparisonMethod>
+        },
+        s => return Err(
+            SkarnError::new("error parsing comparison method")
+            .with_detail(format!("invalid comparison method: '{}'", s))
+        )
+    };
+
+    let preserve = try!(Preserve::from_str(&args.flag_preserve[..]));
+
+    let output_format = try!(OutputFormat::from_str(&args.flag_format[..]));
+    let conflict_policy = try!(ConflictPolicy::from_str(&args.flag_conflict[..]));
+
+    let mut transforms = vec![];
+    for rule in args.flag_transform.iter() {
+        transforms.push(try!(Transform::from_str(rule)));
+    }
+
+    let max_delete = try!(parse_limit(&args.flag_max_delete, "--max-delete"));
+    let max_delete_percent = try!(parse_limit(&args.flag_max_delete_percent, "--max-delete-percent"));
+
+    let backup = try!(parse_backup(&args));
+    let snapshot = try!(parse_snapshot(&args));
+
+    // Watching keeps a single destination directory up to date as changes happen.
+    if args.cmd_watch && (args.flag_dry_run || args.flag_snapshot) {
+        return Err(
+            SkarnError::new("invalid watch options")
+            .with_detail("--dry-run and --snapshot can't be used with watch".to_string())
+        );
+    }
+
+    let dest_dir = match snapshot {
+        Some(ref snapshot) => snapshot.dir(),
+        None => PathBuf::from(&args.arg_dest[..])
+    };
+
+    // Bidirectional syncs copy files as they are, in both directions.
+    if args.cmd_bisync && (args.flag_detect_moves || !transforms.is_empty() || backup.is_some() ||
+                           snapshot.is_some()) {
+        return Err(
+            SkarnError::new("invalid bisync options")
+            .with_detail(
+                "--detect-moves, --transform, --backup-dir and --snapshot can't be used with bisync"
+                .to_string()
+            )
+        );
+    }
+
+    // Bidirectional plans list conflicts, which the JSON formats have no place for.
+    if args.cmd_bisync && output_format != OutputFormat::Itemized {
+        return Err(
+            SkarnError::new("invalid bisync options")
+            .with_detail("--format can only be itemized for bisync".to_string())
+        );
+    }
+
+    let pattern_type = if args.flag_git {
+        Git
+    } else {
+        IncludeFile(PathBuf::from(args.arg_include_file))
+    };
+
     Ok(Config {
         source_dir: PathBuf::from(args.arg_source),
-        dest_dir: PathBuf::from(args.arg_dest),
-        pattern_type: IncludeFile(PathBuf::from(args.arg_include_file)),
-        comparison_method: box Content as Box<ComparisonMethod>,
+        dest_dir: dest_dir,
+        pattern_type: pattern_type,
+        comparison_method: comparison_method,
         delete_behaviour: delete_behaviour,
-        include_by_default: true
+        include_by_default: true,
+        symlink_mode: symlink_mode,
+        detect_moves: args.flag_detect_moves,
+        transforms: transforms,
+        preserve: preserve,
+        dry_run: args.flag_dry_run,
+        output_format: output_format,
+        explain: if args.cmd_explain { Some(PathBuf::from(args.arg_path)) } else { None },
+        bisync_state: if args.cmd_bisync { Some(PathBuf::from(args.arg_state_file)) } else { None },
+        conflict_policy: conflict_policy,
+        watch: args.cmd_watch,
+        debounce: args.flag_debounce,
+        fs: box RealFs as Box<FileSystem>,
+        threads: args.flag_threads,
+        max_delete: max_delete,
+        max_delete_percent: max_delete_percent,
+        expect_mount: args.flag_expect_mount,
+        force: args.flag_force,
+        backup: backup,
+        snapshot: snapshot
+    })
+}
+
+fn parse_backup(args: &Args) -> Result<Option<Backup>, SkarnError> {
+    let retention = try!(parse_retention(
+        &args.flag_backup_keep, "--backup-keep", &args.flag_backup_max_age, "--backup-max-age"
+    ));
+
+    if args.flag_backup_dir.is_empty() {
+        return Ok(None);
+    }
+
+    let dir = PathBuf::from(&args.flag_backup_dir[..]);
+
+    // Backups within the destination would be deleted by the next sync. The paths are compared
+    // once canonicalized, so that different spellings of the same directory are caught.
+    let canonical_dir = try!(canonicalize_partial(&dir));
+    let canonical_dest = try!(canonicalize_partial(Path::new(&args.arg_dest[..])));
+    if canonical_dir.starts_with(&canonical_dest) {
+        return Err(
+            SkarnError::new("invalid backup directory")
+            .with_detail("the backup directory can't be inside the destination".to_string())
+        );
+    }
+
+    Ok(Some(Backup::new(&RealFs, dir, retention)))
+}
+
+/// Canonicalize a path that may not exist yet, by canonicalizing its nearest existing ancestor.
+fn canonicalize_partial(path: &Path) -> io::Result<PathBuf> {
+    let path = try!(env::current_dir()).join(path);
+    let mut existing = path.as_path();
+    let mut missing = vec![];
+
+    loop {
+        match RealFs.canonicalize(existing) {
+            Ok(mut canonical) => {
+                for name in missing.iter().rev() {
+                    canonical.push(name);
+                }
+                return Ok(canonical);
+            },
+            Err(e) => match (e.kind(), existing.parent(), existing.file_name()) {
+                (io::ErrorKind::NotFound, Some(parent), Some(name)) => {
+                    missing.push(name.to_os_string());
+                    existing = parent;
+                },
+                _ => return Err(e)
+            }
+        }
+    }
+}
+
+fn parse_snapshot(args: &Args) -> Result<Option<Snapshot>, SkarnError> {
+    let retention = try!(parse_retention(
+        &args.flag_snapshot_keep, "--snapshot-keep",
+        &args.flag_snapshot_max_age, "--snapshot-max-age"
+    ));
+
+    if !args.flag_snapshot {
+        return Ok(None);
+    }
+
+    // Snapshots start out empty, so there's nothing in them to move.
+    if args.flag_detect_moves {
+        return Err(
+            SkarnError::new("invalid snapshot options")
+            .with_detail("--detect-moves can't be used with --snapshot".to_string())
+        );
+    }
+
+    let snapshot = try!(Snapshot::new(&RealFs, PathBuf::from(&args.arg_dest[..]), retention));
+    Ok(Some(snapshot))
+}
+
+/// Parse the flags for a retention policy, given their values and names.
+fn parse_retention(keep: &str, keep_flag: &str, max_age: &str, max_age_flag: &str)
+-> Result<Retention, SkarnError> {
+    let keep = try!(parse_limit(keep, keep_flag));
+    let max_age_days: Option<i64> = try!(parse_limit(max_age, max_age_flag));
+
+    // The newest directory is the one created by this sync, which is always kept.
+    if keep == Some(0) {
+        return Err(
+            SkarnError::new("invalid retention policy")
+            .with_detail(format!("{} must be at least 1", keep_flag))
+        );
+    }
+
+    Ok(Retention {
+        keep: keep,
+        max_age: max_age_days.map(Duration::days)
     })
 }
+
+/// Parse the value of an optional numeric flag, which is empty if the flag wasn't given.
+fn parse_limit<T: FromStr>(value: &str, flag: &str) -> Result<Option<T>, SkarnError> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse() {
+        Ok(limit) => Ok(Some(limit)),
+        Err(_) => Err(
+            SkarnError::new("error parsing limit")
+            .with_detail(format!("invalid value for {}: '{}'", flag, value))
+        )
+    }
+}
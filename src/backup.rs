@@ -0,0 +1,121 @@
+//! backup.rs, part of Skarn.
+//!
+//! This module moves files that a sync would delete or overwrite into a backup directory,
+//! similar to rsync's `--backup-dir`, so that a mistaken pattern can't destroy data.
+//!
+//! Each sync gets its own dated subdirectory within the backup directory, in which files keep
+//! their paths relative to the destination directory. Old subdirectories are pruned according
+//! to a retention policy once the sync is complete.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libc;
+use time;
+
+use vfs::FileSystem;
+use dated::{unused_dated_name, Retention};
+
+pub struct Backup {
+    /// The directory containing the dated backup directories.
+    pub dir: PathBuf,
+    /// The name of the dated directory for this sync.
+    pub name: String,
+    pub retention: Retention
+}
+
+impl Backup {
+    /// Back up to a new directory within `dir`, named after the current time.
+    ///
+    /// An existing backup directory is never reused, even if it was created in the same second.
+    pub fn new(fs: &FileSystem, dir: PathBuf, retention: Retention) -> Backup {
+        Backup {
+            name: unused_dated_name(fs, &dir, time::now_utc()),
+            dir: dir,
+            retention: retention
+        }
+    }
+
+    /// The directory holding this sync's backups.
+    pub fn current_dir(&self) -> PathBuf {
+        self.dir.join(&self.name)
+    }
+
+    /// Move a file or symlink into the backup directory.
+    ///
+    /// The relative path is the file's path within the destination directory.
+    pub fn back_up(&self, fs: &FileSystem, path: &Path, relative_path: &Path) -> io::Result<()> {
+        let backup_path = self.current_dir().join(relative_path);
+
+        if let Some(parent) = backup_path.parent() {
+            try!(fs.create_dir_all(parent));
+        }
+
+        match fs.rename(path, &backup_path) {
+            // Files can't be renamed onto another file system, so they're copied instead.
+            Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                let metadata = try!(fs.symlink_metadata(path));
+                if metadata.is_symlink() {
+                    try!(fs.symlink(&try!(fs.read_link(path)), &backup_path));
+                } else {
+                    try!(fs.copy(path, &backup_path));
+                }
+                fs.remove_file(path)
+            }
+            result => result
+        }
+    }
+
+    /// Remove old backup directories, according to the retention policy.
+    ///
+    /// The directory for this sync is never removed.
+    pub fn prune(&self, fs: &FileSystem) -> io::Result<Vec<PathBuf>> {
+        let now = time::get_time();
+        self.retention.prune(fs, &self.dir, &self.current_dir(), now)
+    }
+}
+
+// Tests
+
+#[test]
+fn test_backup_deleted_and_overwritten() {
+    use std::io::Read;
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+    use plan::plan;
+    use execute::execute;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "new");
+    fs.add_file("dst/Music/a.flac", "old");
+    fs.add_file("dst/Music/b.flac", "b");
+    fs.add_file("backup/2015-06-21-143000/Music/c.flac", "c");
+
+    let mut config = test_config(fs);
+    config.backup = Some(Backup {
+        dir: PathBuf::from("backup"),
+        name: "2015-06-22-143000".to_string(),
+        retention: Retention { keep: Some(1), max_age: None }
+    });
+
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+    let plan = plan(&matcher, &config).unwrap();
+    for outcome in execute(&plan, &config) {
+        assert!(outcome.result.is_ok());
+    }
+
+    let read = |path: &str| {
+        let mut contents = String::new();
+        config.fs.open(Path::new(path)).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    };
+
+    assert_eq!(read("dst/Music/a.flac"), "new");
+    assert_eq!(read("backup/2015-06-22-143000/Music/a.flac"), "old");
+    assert_eq!(read("backup/2015-06-22-143000/Music/b.flac"), "b");
+    assert!(config.fs.symlink_metadata(Path::new("dst/Music/b.flac")).is_err());
+
+    // The older backup is pruned.
+    assert!(config.fs.symlink_metadata(Path::new("backup/2015-06-21-143000")).is_err());
+}
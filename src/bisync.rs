@@ -0,0 +1,730 @@
+//! bisync.rs, part of Skarn.
+//!
+//! This module implements bidirectional sync, in which changes made to either directory are
+//! copied to the other.
+//!
+//! The size, hash and modification times of every file are recorded in a state file after each
+//! sync. On the next sync, each side's files are compared against the recorded state to work out
+//! which side changed: new and modified files are copied to the other side, and files deleted
+//! from one side are deleted from the other. A file modified on one side and deleted on the other
+//! is copied, so that the modifications aren't lost.
+//!
+//! A file that changed differently on both sides is a conflict, which is resolved according to
+//! the configured `ConflictPolicy`. Conflicts are listed separately from other changes.
+//!
+//! Both directories are filtered with the same `Matcher`, relative to their own roots. Only
+//! regular files are synced; symlinks are skipped unless they are followed.
+
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet};
+
+use rustc_serialize::json;
+use libc;
+use time;
+
+use vfs::{FileSystem, Metadata};
+use matcher::Matcher;
+use matcher::Class::*;
+use config::{Config, ConflictPolicy};
+use config::SymlinkMode::Follow;
+use dated::dated_name;
+use hash::hash_file;
+use path::key_to_path;
+use preserve::preserve_metadata;
+use self::Change::*;
+use self::Status::*;
+
+/// The recorded state of a file that was the same on both sides after the last sync.
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Eq, Debug)]
+pub struct StateEntry {
+    pub size: u64,
+    pub hash: String,
+    pub source_mtime: i64,
+    pub source_mtime_nsec: i64,
+    pub dest_mtime: i64,
+    pub dest_mtime_nsec: i64
+}
+
+/// The state of every file after the last sync, keyed by relative path.
+pub struct State {
+    path: PathBuf,
+    pub entries: BTreeMap<String, StateEntry>
+}
+
+impl State {
+    /// Load the state file, or start with an empty state if it doesn't exist yet.
+    pub fn load(fs: &FileSystem, path: &Path) -> io::Result<State> {
+        let mut entries = BTreeMap::new();
+
+        match fs.open(path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                entries = try!(json::decode(&contents).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("invalid state file: {}", e))
+                }));
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e)
+        }
+
+        Ok(State {
+            path: path.to_path_buf(),
+            entries: entries
+        })
+    }
+
+    /// Write the state back to its file.
+    pub fn save(&self, fs: &FileSystem) -> io::Result<()> {
+        let contents = try!(json::encode(&self.entries).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("unable to encode state: {}", e))
+        }));
+
+        // Write to a temporary file first, so that an interrupted write can't lose the state.
+        let temp_path = self.path.with_extension("tmp");
+        try!(fs.write(&temp_path, contents.as_bytes()));
+        fs.rename(&temp_path, &self.path)
+    }
+}
+
+/// A change needed to bring the two sides back in sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Copy the source's version of the file to the destination.
+    CopyToDest,
+    /// Copy the destination's version of the file to the source.
+    CopyToSource,
+    DeleteFromDest,
+    DeleteFromSource,
+    /// Keep both versions of a conflicting file, by renaming the destination's version with the
+    /// conflict suffix, copying it to the source, and copying the source's version to the
+    /// destination.
+    KeepBoth
+}
+
+impl Change {
+    /// A short description of the change, for display.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CopyToDest => "copy to dest",
+            CopyToSource => "copy to source",
+            DeleteFromDest => "delete from dest",
+            DeleteFromSource => "delete from source",
+            KeepBoth => "keep both"
+        }
+    }
+}
+
+/// How one side's copy of a file compares to the last sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Status {
+    /// The file doesn't exist, and didn't at the last sync.
+    Absent,
+    /// The file has been deleted since the last sync.
+    Deleted,
+    /// The file is the same as at the last sync, with the given hash.
+    Unchanged(String),
+    /// The file is new or modified, with the given hash.
+    Changed(String)
+}
+
+/// The changes needed to sync the two sides.
+pub struct BisyncPlan {
+    /// The changes to make, sorted by path.
+    pub changes: Vec<(PathBuf, Change)>,
+    /// The files that changed differently on both sides, sorted by path, with the change that
+    /// resolves each conflict. Conflicts that are skipped have no change.
+    pub conflicts: Vec<(PathBuf, Option<Change>)>,
+    /// The suffix added to the names of conflicting files when keeping both versions, like
+    /// `.conflict-<host>-<date>`.
+    pub conflict_suffix: String,
+    /// The paths that the destination's versions of conflicting files are renamed to when keeping
+    /// both, which are numbered if the suffixed name is already taken.
+    conflict_paths: BTreeMap<PathBuf, PathBuf>,
+    /// The recorded paths that the matcher now excludes, whose state is dropped.
+    excluded: Vec<String>,
+    /// The paths that will be the same on both sides once the changes are made.
+    in_sync: BTreeMap<PathBuf, Expected>
+}
+
+/// The contents a file is expected to have on both sides after a sync, along with the metadata of
+/// each side's copy while planning, for the sides that the sync doesn't write to.
+struct Expected {
+    hash: String,
+    source: Option<Metadata>,
+    dest: Option<Metadata>
+}
+
+impl BisyncPlan {
+    /// The number of files to be deleted from the source and destination respectively.
+    pub fn num_deletions(&self) -> (usize, usize) {
+        let count = |change| self.changes.iter().filter(|&&(_, c)| c == change).count();
+        (count(DeleteFromSource), count(DeleteFromDest))
+    }
+}
+
+/// The result of making a single change, or of resolving a conflict.
+pub struct BisyncOutcome {
+    pub path: PathBuf,
+    /// The change made, which is only missing for skipped conflicts.
+    pub change: Option<Change>,
+    pub conflict: bool,
+    pub result: io::Result<()>
+}
+
+/// Compare both sides against the recorded state to work out the changes needed.
+pub fn plan_bisync(matcher: &Matcher, config: &Config, state: &State) -> io::Result<BisyncPlan> {
+    let fs = &*config.fs;
+    let source_files = try!(scan(matcher, config, &config.source_dir));
+    let dest_files = try!(scan(matcher, config, &config.dest_dir));
+
+    // Recorded files that are no longer included are forgotten, rather than treated as deleted.
+    let (included, excluded): (Vec<&String>, Vec<&String>) = state.entries.keys().partition(|key| {
+        is_included(matcher, config, Path::new(&key[..]))
+    });
+
+    let mut paths: BTreeSet<PathBuf> = source_files.keys().chain(dest_files.keys()).cloned().collect();
+    paths.extend(included.into_iter().map(PathBuf::from));
+
+    let mut changes = vec![];
+    let mut conflicts = vec![];
+    let mut conflict_paths = BTreeMap::new();
+    let mut in_sync = BTreeMap::new();
+    let conflict_suffix = format!(".conflict-{}-{}", hostname(), dated_name(&time::now_utc()));
+
+    for path in paths {
+        let entry = state.entries.get(&path_key(&path));
+
+        let source_path = config.source_dir.join(&path);
+        let dest_path = config.dest_dir.join(&path);
+        let source_mtime = entry.map(|e| (e.source_mtime, e.source_mtime_nsec));
+        let dest_mtime = entry.map(|e| (e.dest_mtime, e.dest_mtime_nsec));
+
+        let source = try!(status(fs, &source_path, source_files.get(&path), source_mtime, entry));
+        let dest = try!(status(fs, &dest_path, dest_files.get(&path), dest_mtime, entry));
+
+        let source_metadata = source_files.get(&path).cloned();
+        let dest_metadata = dest_files.get(&path).cloned();
+        let mut resolution = None;
+
+        let (change, hash) = match (source, dest) {
+            (Unchanged(hash), Unchanged(_)) => (None, Some(hash)),
+            (Changed(s), Changed(d)) => {
+                if s == d {
+                    (None, Some(s))
+                } else {
+                    resolution = resolve(config.conflict_policy, &source_files[&path],
+                                         &dest_files[&path]);
+                    conflicts.push((path.clone(), resolution));
+
+                    // Resolved conflicts leave the two sides in sync, like any other change.
+                    match resolution {
+                        Some(CopyToSource) => (None, Some(d)),
+                        Some(KeepBoth) => {
+                            let renamed = unused_conflict_path(config, &path, &conflict_suffix);
+
+                            // Conflict copies that the matcher excludes aren't tracked.
+                            if is_included(matcher, config, &renamed) {
+                                in_sync.insert(renamed.clone(), Expected {
+                                    hash: d,
+                                    source: None,
+                                    dest: dest_metadata
+                                });
+                            }
+                            conflict_paths.insert(path.clone(), renamed);
+                            (None, Some(s))
+                        },
+                        Some(_) => (None, Some(s)),
+                        None => (None, None)
+                    }
+                }
+            },
+            (Changed(hash), _) => (Some(CopyToDest), Some(hash)),
+            (_, Changed(hash)) => (Some(CopyToSource), Some(hash)),
+            (Deleted, Unchanged(_)) => (Some(DeleteFromDest), None),
+            (Unchanged(_), Deleted) => (Some(DeleteFromSource), None),
+            _ => (None, None)
+        };
+
+        if let Some(change) = change {
+            changes.push((path.clone(), change));
+        }
+        if let Some(hash) = hash {
+            // The side being copied to is expected to change.
+            let copied_to_source = change.or(resolution) == Some(CopyToSource);
+            let copied_to_dest = change.or(resolution).map_or(false, |c| c != CopyToSource);
+            in_sync.insert(path, Expected {
+                hash: hash,
+                source: if copied_to_source { None } else { source_metadata },
+                dest: if copied_to_dest { None } else { dest_metadata }
+            });
+        }
+    }
+
+    Ok(BisyncPlan {
+        changes: changes,
+        conflicts: conflicts,
+        conflict_suffix: conflict_suffix,
+        conflict_paths: conflict_paths,
+        excluded: excluded.into_iter().cloned().collect(),
+        in_sync: in_sync
+    })
+}
+
+/// Make the planned changes, and update the state to match.
+///
+/// The state of a file is only updated if its change succeeded, so failed changes are retried
+/// on the next sync. Skipped conflicts keep their old state, as do files that changed while
+/// syncing, so that their changes are picked up by the next sync.
+pub fn execute_bisync(plan: &BisyncPlan, config: &Config, state: &mut State) -> Vec<BisyncOutcome> {
+    let fs = &*config.fs;
+    let mut outcomes = vec![];
+    let mut failed = BTreeSet::new();
+
+    let changes = plan.changes.iter().map(|&(ref path, change)| (path, Some(change), false));
+    let conflicts = plan.conflicts.iter().map(|&(ref path, change)| (path, change, true));
+
+    for (path, change, conflict) in changes.chain(conflicts) {
+        let result = match change {
+            Some(change) => apply(change, path, plan, config),
+            None => Ok(())
+        };
+
+        if result.is_err() {
+            failed.insert(path.clone());
+            if let Some(renamed) = plan.conflict_paths.get(path) {
+                failed.insert(renamed.clone());
+            }
+        }
+        outcomes.push(BisyncOutcome {
+            path: path.clone(),
+            change: change,
+            conflict: conflict,
+            result: result
+        });
+    }
+
+    for (path, expected) in plan.in_sync.iter() {
+        if failed.contains(path) {
+            continue;
+        }
+        let source_path = config.source_dir.join(path);
+        let dest_path = config.dest_dir.join(path);
+
+        let source = fs.metadata(&source_path);
+        let dest = fs.metadata(&dest_path);
+        if let (Ok(source), Ok(dest)) = (source, dest) {
+            let source_ok = has_contents(fs, &source_path, &source, expected.source, &expected.hash);
+            let dest_ok = has_contents(fs, &dest_path, &dest, expected.dest, &expected.hash);
+
+            if !source_ok || !dest_ok {
+                debug!("Changed while syncing: {}", path.display());
+                continue;
+            }
+            state.entries.insert(path_key(path), StateEntry {
+                size: source.len,
+                hash: expected.hash.clone(),
+                source_mtime: source.mtime,
+                source_mtime_nsec: source.mtime_nsec,
+                dest_mtime: dest.mtime,
+                dest_mtime_nsec: dest.mtime_nsec
+            });
+        }
+    }
+
+    // Forget files that no longer exist on either side, or that are now excluded.
+    let mut gone: Vec<String> = state.entries.keys().filter(|key| {
+        let path = Path::new(&key[..]);
+        fs.symlink_metadata(&config.source_dir.join(path)).is_err() &&
+            fs.symlink_metadata(&config.dest_dir.join(path)).is_err()
+    }).cloned().collect();
+    gone.extend(plan.excluded.iter().cloned());
+    for key in gone {
+        state.entries.remove(&key);
+    }
+
+    outcomes
+}
+
+/// List the included regular files beneath a directory, with their metadata.
+fn scan(matcher: &Matcher, config: &Config, root: &Path) -> io::Result<BTreeMap<PathBuf, Metadata>> {
+    let fs = &*config.fs;
+    let follow_links = config.symlink_mode == Follow;
+    let (include_paths, _) = try!(matcher.classify_recursive(
+        fs, root, config.include_by_default, follow_links, config.threads
+    ));
+
+    let mut files = BTreeMap::new();
+    for key in include_paths.keys() {
+        let relative_path = key_to_path(&key[..]);
+        let path = root.join(&relative_path);
+        let metadata = if follow_links {
+            try!(fs.metadata(&path))
+        } else {
+            try!(fs.symlink_metadata(&path))
+        };
+
+        if metadata.is_file() {
+            files.insert(relative_path, metadata);
+        } else {
+            debug!("Skipping non-file: {}", path.display());
+        }
+    }
+    Ok(files)
+}
+
+/// Work out how a file has changed since the last sync.
+///
+/// Files whose size and modification time match the recorded state are assumed to be unchanged.
+/// The time is compared to the nanosecond, so that an edit made in the same second as the last
+/// sync isn't missed. Otherwise the file is hashed, so that a file that was only touched isn't
+/// treated as changed.
+fn status(fs: &FileSystem, path: &Path, metadata: Option<&Metadata>,
+          recorded_mtime: Option<(i64, i64)>, entry: Option<&StateEntry>) -> io::Result<Status> {
+    let (metadata, entry) = match (metadata, entry) {
+        (None, None) => return Ok(Absent),
+        (None, Some(_)) => return Ok(Deleted),
+        (Some(_), None) => return Ok(Changed(try!(hash_file(fs, path)))),
+        (Some(metadata), Some(entry)) => (metadata, entry)
+    };
+
+    if metadata.len == entry.size && Some((metadata.mtime, metadata.mtime_nsec)) == recorded_mtime {
+        return Ok(Unchanged(entry.hash.clone()));
+    }
+
+    let hash = try!(hash_file(fs, path));
+    if hash == entry.hash {
+        Ok(Unchanged(hash))
+    } else {
+        Ok(Changed(hash))
+    }
+}
+
+/// Check that one side's copy of a file has the expected contents after a sync.
+///
+/// A copy that the sync didn't write to is assumed to be unchanged if its size and modification
+/// time match those seen while planning. Otherwise the file is hashed.
+fn has_contents(fs: &FileSystem, path: &Path, metadata: &Metadata, planned: Option<Metadata>,
+                hash: &str) -> bool {
+    let unchanged = planned.map_or(false, |planned| {
+        (planned.len, planned.mtime, planned.mtime_nsec) ==
+            (metadata.len, metadata.mtime, metadata.mtime_nsec)
+    });
+    unchanged || hash_file(fs, path).map(|h| h == hash).unwrap_or(false)
+}
+
+/// Choose how to resolve a conflict, given the metadata of each side's version.
+///
+/// When keeping the newer version, the source's version wins if both were modified at the
+/// same time.
+fn resolve(policy: ConflictPolicy, source: &Metadata, dest: &Metadata) -> Option<Change> {
+    match policy {
+        ConflictPolicy::Newer => {
+            if (dest.mtime, dest.mtime_nsec) > (source.mtime, source.mtime_nsec) {
+                Some(CopyToSource)
+            } else {
+                Some(CopyToDest)
+            }
+        },
+        ConflictPolicy::Source => Some(CopyToDest),
+        ConflictPolicy::Both => Some(KeepBoth),
+        ConflictPolicy::Skip => None
+    }
+}
+
+fn apply(change: Change, path: &Path, plan: &BisyncPlan, config: &Config) -> io::Result<()> {
+    let fs = &*config.fs;
+    let source = config.source_dir.join(path);
+    let dest = config.dest_dir.join(path);
+
+    match change {
+        CopyToDest => copy_file(&source, &dest, config),
+        CopyToSource => copy_file(&dest, &source, config),
+        DeleteFromDest => delete_file(fs, &config.dest_dir, path),
+        DeleteFromSource => delete_file(fs, &config.source_dir, path),
+        KeepBoth => {
+            let renamed = &plan.conflict_paths[path];
+            let dest_renamed = config.dest_dir.join(renamed);
+            let source_renamed = config.source_dir.join(renamed);
+
+            // Never overwrite a file created since planning.
+            if fs.symlink_metadata(&dest_renamed).is_ok() ||
+               fs.symlink_metadata(&source_renamed).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", renamed.display())
+                ));
+            }
+            try!(fs.rename(&dest, &dest_renamed));
+            try!(copy_file(&dest_renamed, &source_renamed, config));
+            copy_file(&source, &dest, config)
+        }
+    }
+}
+
+/// The path that the destination's version of a conflicting file is renamed to.
+fn conflict_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Find a conflict path that exists on neither side, numbering the suffix if necessary, as in
+/// `.conflict-<host>-<date>-2`.
+fn unused_conflict_path(config: &Config, path: &Path, suffix: &str) -> PathBuf {
+    let fs = &*config.fs;
+    let exists = |renamed: &Path| {
+        fs.symlink_metadata(&config.source_dir.join(renamed)).is_ok() ||
+            fs.symlink_metadata(&config.dest_dir.join(renamed)).is_ok()
+    };
+
+    let mut renamed = conflict_path(path, suffix);
+    let mut number = 1;
+    while exists(&renamed) {
+        number += 1;
+        renamed = conflict_path(path, &format!("{}-{}", suffix, number));
+    }
+    renamed
+}
+
+/// Classify a file, using the tie-breaker for files that can't be classified.
+fn is_included(matcher: &Matcher, config: &Config, relative_path: &Path) -> bool {
+    match matcher.classify(relative_path, false) {
+        Included => true,
+        Excluded => false,
+        Both => config.include_by_default
+    }
+}
+
+/// The name of this machine, for naming conflicting files.
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let result = unsafe {
+        libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len() as libc::size_t)
+    };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let length = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+fn copy_file(from: &Path, to: &Path, config: &Config) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        try!(config.fs.create_dir_all(parent));
+    }
+    try!(config.fs.copy(from, to));
+    preserve_metadata(&*config.fs, from, to, &config.preserved())
+}
+
+/// Delete a file, along with any parent directories it leaves empty.
+fn delete_file(fs: &FileSystem, root: &Path, relative_path: &Path) -> io::Result<()> {
+    try!(fs.remove_file(&root.join(relative_path)));
+
+    let mut dir = relative_path.parent();
+    while let Some(parent) = dir {
+        if parent.as_os_str().is_empty() || !try!(fs.read_dir(&root.join(parent))).is_empty() {
+            break;
+        }
+        try!(fs.remove_dir(&root.join(parent)));
+        dir = parent.parent();
+    }
+    Ok(())
+}
+
+/// The key for a path in the state file.
+fn path_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+// Tests
+
+#[test]
+fn test_bisync() {
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.add_file("src/Music/c.flac", "c");
+    fs.add_file("dst/Music/b.flac", "b");
+    fs.add_file("dst/Music/c.flac", "c");
+    fs.add_file("dst/Videos/v.mkv", "v");
+
+    let config = test_config(fs);
+    let fs = &*config.fs;
+    let matcher = parse_include_file("Music\n/!/ Videos", Path::new("test.ska")).unwrap();
+    let mut state = State::load(fs, Path::new("state.json")).unwrap();
+
+    let changes = |state: &State| -> Vec<(String, Change)> {
+        plan_bisync(&matcher, &config, state).unwrap().changes.into_iter()
+            .map(|(path, change)| (path_key(&path), change))
+            .collect()
+    };
+
+    // New files are copied in both directions, and excluded files are left alone.
+    assert_eq!(changes(&state), vec![
+        ("Music/a.flac".to_string(), CopyToDest),
+        ("Music/b.flac".to_string(), CopyToSource)
+    ]);
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+    for outcome in execute_bisync(&plan, &config, &mut state) {
+        assert!(outcome.result.is_ok());
+    }
+    assert_eq!(state.entries.len(), 3);
+    assert!(changes(&state).is_empty());
+
+    // Touching a file without changing it isn't a change.
+    fs.write(Path::new("src/Music/a.flac"), b"a").unwrap();
+    assert!(changes(&state).is_empty());
+
+    fs.write(Path::new("dst/Music/a.flac"), b"A").unwrap();
+    fs.remove_file(Path::new("src/Music/b.flac")).unwrap();
+    fs.write(Path::new("src/Music/c.flac"), b"c1").unwrap();
+    fs.write(Path::new("dst/Music/c.flac"), b"c2").unwrap();
+    assert_eq!(changes(&state), vec![
+        ("Music/a.flac".to_string(), CopyToSource),
+        ("Music/b.flac".to_string(), DeleteFromDest)
+    ]);
+
+    // Conflicts are skipped by default, and listed separately.
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+    assert_eq!(plan.conflicts, vec![(PathBuf::from("Music/c.flac"), None)]);
+    execute_bisync(&plan, &config, &mut state);
+    assert!(fs.metadata(Path::new("dst/Music/b.flac")).is_err());
+    assert!(!state.entries.contains_key("Music/b.flac"));
+    assert!(changes(&state).is_empty());
+    assert_eq!(plan_bisync(&matcher, &config, &state).unwrap().conflicts.len(), 1);
+
+    state.save(fs).unwrap();
+    assert_eq!(State::load(fs, Path::new("state.json")).unwrap().entries, state.entries);
+}
+
+#[test]
+fn test_conflict_policies() {
+    use std::io::Read;
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+
+    // Sync a file, then change it differently on both sides, the destination last.
+    let resolve_with = |policy: ConflictPolicy| {
+        let fs = MemoryFs::new();
+        fs.add_file("src/Music/a.flac", "a");
+        fs.add_file("dst/Music/a.flac", "a");
+
+        let mut config = test_config(fs);
+        config.conflict_policy = policy;
+        let mut state = State::load(&*config.fs, Path::new("state.json")).unwrap();
+        let plan = plan_bisync(&matcher, &config, &state).unwrap();
+        execute_bisync(&plan, &config, &mut state);
+
+        config.fs.write(Path::new("src/Music/a.flac"), b"source").unwrap();
+        config.fs.write(Path::new("dst/Music/a.flac"), b"dest").unwrap();
+        let plan = plan_bisync(&matcher, &config, &state).unwrap();
+        for outcome in execute_bisync(&plan, &config, &mut state) {
+            assert!(outcome.conflict && outcome.result.is_ok());
+        }
+        assert!(plan_bisync(&matcher, &config, &state).unwrap().conflicts.is_empty() ||
+                policy == ConflictPolicy::Skip);
+
+        let read = |path: &str| {
+            let mut contents = String::new();
+            config.fs.open(Path::new(path)).unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+        // The destination's version is kept under the conflict name on both sides.
+        let renamed = format!("Music/a.flac{}", plan.conflict_suffix);
+        let kept = if config.fs.metadata(&Path::new("src").join(&renamed)).is_ok() {
+            Some(read(&format!("dst/{}", renamed)))
+        } else {
+            None
+        };
+        (read("src/Music/a.flac"), read("dst/Music/a.flac"), kept)
+    };
+
+    let s = |s: &str| s.to_string();
+    assert_eq!(resolve_with(ConflictPolicy::Newer), (s("dest"), s("dest"), None));
+    assert_eq!(resolve_with(ConflictPolicy::Source), (s("source"), s("source"), None));
+    assert_eq!(resolve_with(ConflictPolicy::Skip), (s("source"), s("dest"), None));
+    assert_eq!(resolve_with(ConflictPolicy::Both), (s("source"), s("source"), Some(s("dest"))));
+}
+
+#[test]
+fn test_changes_while_syncing() {
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.add_file("src/Music/b.flac", "b");
+
+    let config = test_config(fs);
+    let fs = &*config.fs;
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+    let mut state = State::load(fs, Path::new("state.json")).unwrap();
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+    execute_bisync(&plan, &config, &mut state);
+
+    // Files changed after planning keep their old state, rather than the planned hash.
+    fs.write(Path::new("src/Music/a.flac"), b"a1").unwrap();
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+    assert_eq!(plan.changes, vec![(PathBuf::from("Music/a.flac"), CopyToDest)]);
+
+    fs.write(Path::new("src/Music/a.flac"), b"a22").unwrap();
+    fs.write(Path::new("dst/Music/b.flac"), b"b2").unwrap();
+    let old_b = state.entries["Music/b.flac"].clone();
+    execute_bisync(&plan, &config, &mut state);
+    assert_eq!(state.entries["Music/b.flac"], old_b);
+
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+    assert_eq!(plan.changes, vec![(PathBuf::from("Music/b.flac"), CopyToSource)]);
+}
+
+#[test]
+fn test_conflict_copies() {
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.add_file("dst/Music/a.flac", "a");
+    fs.add_file("dst/Music/b.flac.conflict-x", "b");
+
+    let mut config = test_config(fs);
+    config.conflict_policy = ConflictPolicy::Both;
+    let matcher = parse_include_file("Music\n/!/ Music/*.conflict-*", Path::new("test.ska")).unwrap();
+
+    // Taken names are never reused.
+    let path = Path::new("Music/b.flac");
+    assert_eq!(unused_conflict_path(&config, path, ".conflict-x"),
+               PathBuf::from("Music/b.flac.conflict-x-2"));
+
+    let mut state = State::load(&*config.fs, Path::new("state.json")).unwrap();
+    state.entries.insert("Music/b.flac.conflict-x".to_string(), StateEntry {
+        size: 1,
+        hash: String::new(),
+        source_mtime: 0,
+        source_mtime_nsec: 0,
+        dest_mtime: 0,
+        dest_mtime_nsec: 0
+    });
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+    execute_bisync(&plan, &config, &mut state);
+
+    // Conflict copies that are excluded aren't tracked, even if they were before.
+    config.fs.write(Path::new("src/Music/a.flac"), b"source").unwrap();
+    config.fs.write(Path::new("dst/Music/a.flac"), b"dest").unwrap();
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+    for outcome in execute_bisync(&plan, &config, &mut state) {
+        assert!(outcome.result.is_ok());
+    }
+    assert_eq!(state.entries.keys().collect::<Vec<_>>(), vec!["Music/a.flac"]);
+}
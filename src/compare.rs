@@ -4,28 +4,43 @@
 
 use std::io;
 use std::path::Path;
-use std::fs::File;
 use std::io::Read;
 
+use hash::{HashCache, hash_file};
+use vfs::FileSystem;
+
 pub trait ComparisonMethod {
-    fn same_file(&self, &Path, &Path) -> io::Result<bool>;
+    fn same_file(&self, &FileSystem, &Path, &Path) -> io::Result<bool>;
+
+    /// Called once all comparisons are complete, to persist any state.
+    fn finish(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether files are compared by modification time, in which case copies must keep the
+    /// modification times of their source files, or every sync would copy them again.
+    fn compares_mtimes(&self) -> bool {
+        false
+    }
+
+    /// Compute the hash of a file's contents, which is cached if the method caches hashes.
+    fn hash(&self, fs: &FileSystem, path: &Path) -> io::Result<String> {
+        hash_file(fs, path)
+    }
 }
 
 #[derive(Clone, Copy)]
 pub struct Content;
 
 impl ComparisonMethod for Content {
-    fn same_file(&self, x: &Path, y: &Path) -> io::Result<bool> {
-        let x_file = try!(File::open(x));
-        let y_file = try!(File::open(y));
-
-        let x_size = try!(x_file.metadata()).len();
-        let y_size = try!(y_file.metadata()).len();
-
-        if x_size != y_size {
+    fn same_file(&self, fs: &FileSystem, x: &Path, y: &Path) -> io::Result<bool> {
+        if try!(fs.metadata(x)).len != try!(fs.metadata(y)).len {
             return Ok(false);
         }
 
+        let x_file = try!(fs.open(x));
+        let y_file = try!(fs.open(y));
+
         for (xbr, ybr) in x_file.bytes().zip(y_file.bytes()) {
             let xb = try!(xbr);
             let yb = try!(ybr);
@@ -37,3 +52,59 @@ impl ComparisonMethod for Content {
         Ok(true)
     }
 }
+
+/// Compare files by size and modification time, without reading their contents.
+///
+/// Modification times are compared to the nearest second. Destinations with coarse timestamps
+/// (like FAT's two second resolution) can be accommodated by a non-zero tolerance.
+#[derive(Clone, Copy)]
+pub struct Metadata {
+    /// Maximum difference in modification times, in seconds.
+    pub mtime_tolerance: u64
+}
+
+impl ComparisonMethod for Metadata {
+    fn same_file(&self, fs: &FileSystem, x: &Path, y: &Path) -> io::Result<bool> {
+        let x_metadata = try!(fs.metadata(x));
+        let y_metadata = try!(fs.metadata(y));
+
+        if x_metadata.len != y_metadata.len {
+            return Ok(false);
+        }
+
+        let mtime_difference = (x_metadata.mtime - y_metadata.mtime).abs() as u64;
+
+        Ok(mtime_difference <= self.mtime_tolerance)
+    }
+
+    fn compares_mtimes(&self) -> bool {
+        true
+    }
+}
+
+/// Compare files by their SHA-256 hashes, which are cached between runs.
+pub struct Hash {
+    pub cache: HashCache
+}
+
+impl ComparisonMethod for Hash {
+    fn same_file(&self, fs: &FileSystem, x: &Path, y: &Path) -> io::Result<bool> {
+        // Files of different sizes can't have the same contents, so avoid hashing them.
+        if try!(fs.metadata(x)).len != try!(fs.metadata(y)).len {
+            return Ok(false);
+        }
+
+        let x_hash = try!(self.cache.hash(fs, x));
+        let y_hash = try!(self.cache.hash(fs, y));
+
+        Ok(x_hash == y_hash)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.cache.save()
+    }
+
+    fn hash(&self, fs: &FileSystem, path: &Path) -> io::Result<String> {
+        self.cache.hash(fs, path)
+    }
+}
@@ -1,11 +1,17 @@
 //! config.rs, part of Skarn.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashSet;
 
 use compare::ComparisonMethod;
+use transform::Transform;
+use backup::Backup;
+use snapshot::Snapshot;
+use vfs::FileSystem;
 use error::Error;
 use self::DeleteBehaviour::*;
+use self::SymlinkMode::*;
+use self::Preserve::*;
 
 pub enum PatternSource {
     IncludeFile(PathBuf),
@@ -28,7 +34,7 @@ impl DeleteBehaviour {
                 "excluded" => try!(set.insert_all(&[ExcludedEquiv, ExcludedNoEquiv])),
                 "included-no-equiv" => try!(set.insert_all(&[IncludedNoEquiv])),
                 "excluded-equiv" => try!(set.insert_all(&[ExcludedEquiv])),
-                "excluded-no-equiv" => try!(set.insert_all(&[ExcludedEquiv])),
+                "excluded-no-equiv" => try!(set.insert_all(&[ExcludedNoEquiv])),
                 _ => return Err(
                     Error::new("error parsing delete behaviour string")
                     .with_detail(format!("invalid option: '{}'", s))
@@ -37,6 +43,128 @@ impl DeleteBehaviour {
         }
         Ok(set)
     }
+
+    /// The name used for this delete behaviour on the command-line.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            IncludedNoEquiv => "included-no-equiv",
+            ExcludedEquiv => "excluded-equiv",
+            ExcludedNoEquiv => "excluded-no-equiv"
+        }
+    }
+}
+
+/// How symlinks in the source directory are treated.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum SymlinkMode {
+    /// Copy links verbatim, so that the copy points to the same target as the original.
+    Copy,
+    /// Copy links, rewriting absolute targets inside the source directory to point inside the
+    /// destination directory.
+    Rewrite,
+    /// Follow links, copying whatever they point to.
+    Follow
+}
+
+impl SymlinkMode {
+    pub fn from_str(mode_string: &str) -> Result<SymlinkMode, Error> {
+        match mode_string {
+            "copy" => Ok(Copy),
+            "rewrite" => Ok(Rewrite),
+            "follow" => Ok(Follow),
+            _ => Err(
+                Error::new("error parsing symlink mode")
+                .with_detail(format!("invalid symlink mode: '{}'", mode_string))
+            )
+        }
+    }
+}
+
+/// File metadata that may be preserved when copying.
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub enum Preserve {
+    /// Permission bits.
+    Mode,
+    /// Owner and group, which can only be set when running as root.
+    Ownership,
+    /// Access and modification times.
+    Times
+}
+
+impl Preserve {
+    pub fn from_str(preserve_string: &str) -> Result<HashSet<Preserve>, Error> {
+        let mut set = HashSet::new();
+        if preserve_string.is_empty() {
+            return Ok(set);
+        }
+        for s in preserve_string.split(',') {
+            let values = match s {
+                "all" => vec![Mode, Ownership, Times],
+                "mode" => vec![Mode],
+                "owner" => vec![Ownership],
+                "times" => vec![Times],
+                _ => return Err(
+                    Error::new("error parsing preserve string")
+                    .with_detail(format!("invalid option: '{}'", s))
+                )
+            };
+            set.extend(values.into_iter());
+        }
+        Ok(set)
+    }
+}
+
+/// How the plan for a dry run is printed.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum OutputFormat {
+    /// One line per change, for people to read.
+    Itemized,
+    /// A single JSON object.
+    Json,
+    /// One JSON object per line.
+    Ndjson
+}
+
+impl OutputFormat {
+    pub fn from_str(format_string: &str) -> Result<OutputFormat, Error> {
+        match format_string {
+            "itemized" => Ok(OutputFormat::Itemized),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            _ => Err(
+                Error::new("error parsing output format")
+                .with_detail(format!("invalid output format: '{}'", format_string))
+            )
+        }
+    }
+}
+
+/// How a bisync resolves a file that changed differently on both sides.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ConflictPolicy {
+    /// Keep whichever version was modified most recently.
+    Newer,
+    /// Keep the source's version.
+    Source,
+    /// Keep both versions, renaming the destination's version.
+    Both,
+    /// Leave both versions alone, and report the conflict.
+    Skip
+}
+
+impl ConflictPolicy {
+    pub fn from_str(policy_string: &str) -> Result<ConflictPolicy, Error> {
+        match policy_string {
+            "newer" => Ok(ConflictPolicy::Newer),
+            "source" => Ok(ConflictPolicy::Source),
+            "both" => Ok(ConflictPolicy::Both),
+            "skip" => Ok(ConflictPolicy::Skip),
+            _ => Err(
+                Error::new("error parsing conflict policy")
+                .with_detail(format!("invalid conflict policy: '{}'", policy_string))
+            )
+        }
+    }
 }
 
 trait InsertAll {
@@ -67,4 +195,110 @@ pub struct Config {
     pub comparison_method: Box<ComparisonMethod + 'static>,
     pub delete_behaviour: HashSet<DeleteBehaviour>,
     pub include_by_default: bool,
+    pub symlink_mode: SymlinkMode,
+    pub detect_moves: bool,
+    pub transforms: Vec<Transform>,
+    pub preserve: HashSet<Preserve>,
+    pub dry_run: bool,
+    pub output_format: OutputFormat,
+    /// A path to explain the classification of, instead of syncing.
+    pub explain: Option<PathBuf>,
+    /// Sync in both directions, using the given state file to work out which side changed.
+    pub bisync_state: Option<PathBuf>,
+    /// How a bisync resolves files that changed differently on both sides.
+    pub conflict_policy: ConflictPolicy,
+    /// Keep syncing changes to the source directory after the initial sync.
+    pub watch: bool,
+    /// How long to wait for a burst of changes to end before syncing them, in milliseconds.
+    pub debounce: u32,
+    /// The file system containing the source and destination directories.
+    pub fs: Box<FileSystem>,
+    /// The number of threads used to traverse the source and destination directories.
+    pub threads: usize,
+    /// Abort if more than this many paths would be deleted.
+    pub max_delete: Option<usize>,
+    /// Abort if more than this percentage of the paths in the destination (or in either
+    /// directory, for bidirectional syncs) would be deleted.
+    pub max_delete_percent: Option<f64>,
+    /// Abort unless the source directory (or both directories, for bidirectional syncs) is a
+    /// mount point.
+    pub expect_mount: bool,
+    /// Ignore the deletion safety checks.
+    pub force: bool,
+    /// Move deleted and overwritten files into a backup directory, rather than removing them.
+    pub backup: Option<Backup>,
+    /// Sync into a new snapshot within the destination directory, which is the snapshot's
+    /// directory rather than the directory containing all of the snapshots.
+    pub snapshot: Option<Snapshot>,
+}
+
+impl Config {
+    /// The directory that the source directory is compared against when planning a sync.
+    ///
+    /// This is the destination directory, except when taking a snapshot after an earlier one,
+    /// when it is the previous snapshot.
+    pub fn compare_dir(&self) -> &Path {
+        match self.snapshot {
+            Some(Snapshot { previous: Some(ref previous), .. }) => previous,
+            _ => &self.dest_dir
+        }
+    }
+
+    /// The metadata preserved on copies, which always includes modification times if the
+    /// comparison method compares them.
+    pub fn preserved(&self) -> HashSet<Preserve> {
+        let mut preserve = self.preserve.clone();
+        if self.comparison_method.compares_mtimes() {
+            preserve.insert(Times);
+        }
+        preserve
+    }
+}
+
+// Tests
+
+/// A configuration for syncing `src` to `dst` within an in-memory file system, deleting everything
+/// extraneous.
+#[cfg(test)]
+pub fn test_config(fs: ::vfs::MemoryFs) -> Config {
+    use compare::Content;
+
+    Config {
+        source_dir: PathBuf::from("src"),
+        dest_dir: PathBuf::from("dst"),
+        pattern_type: PatternSource::IncludeFile(PathBuf::from("test.ska")),
+        comparison_method: box Content as Box<ComparisonMethod>,
+        delete_behaviour: DeleteBehaviour::from_str("all").unwrap(),
+        include_by_default: true,
+        symlink_mode: Copy,
+        detect_moves: false,
+        transforms: vec![],
+        preserve: HashSet::new(),
+        dry_run: true,
+        output_format: OutputFormat::Itemized,
+        explain: None,
+        bisync_state: None,
+        conflict_policy: ConflictPolicy::Skip,
+        watch: false,
+        debounce: 0,
+        fs: box fs as Box<FileSystem>,
+        threads: 2,
+        max_delete: None,
+        max_delete_percent: None,
+        expect_mount: false,
+        force: false,
+        backup: None,
+        snapshot: None
+    }
+}
+
+#[test]
+fn test_delete_behaviour_strings() {
+    let parse = |s: &str| DeleteBehaviour::from_str(s).unwrap();
+
+    assert_eq!(parse("excluded-no-equiv"), [ExcludedNoEquiv].iter().cloned().collect());
+    assert_eq!(parse("excluded-equiv"), [ExcludedEquiv].iter().cloned().collect());
+    assert_eq!(parse("included-no-equiv,excluded"), parse("all"));
+    assert!(DeleteBehaviour::from_str("excluded,excluded-equiv").is_err());
+    assert!(DeleteBehaviour::from_str("").is_err());
 }
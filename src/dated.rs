@@ -0,0 +1,160 @@
+//! dated.rs, part of Skarn.
+//!
+//! This module manages directories of dated subdirectories, like the backup directories kept
+//! by `--backup-dir`, and prunes old subdirectories according to a retention policy.
+//!
+//! Subdirectories are named after the UTC time they were created, like `2015-06-21-143000`,
+//! so that they sort chronologically. Entries with other names are left alone.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use time::{self, Tm, Timespec, Duration};
+
+use vfs::FileSystem;
+use path::remove_tree;
+
+const DATE_FORMAT: &'static str = "%Y-%m-%d-%H%M%S";
+
+/// The name of the dated subdirectory for a time.
+pub fn dated_name(tm: &Tm) -> String {
+    format!("{}", tm.to_utc().strftime(DATE_FORMAT).unwrap())
+}
+
+/// The name for a new dated subdirectory of `dir`, created at the given time.
+///
+/// If a subdirectory with that name already exists, as when two syncs run in the same second,
+/// the first unused name for a later second is used instead, so that syncs never share one.
+pub fn unused_dated_name(fs: &FileSystem, dir: &Path, tm: Tm) -> String {
+    let mut tm = tm;
+    loop {
+        let name = dated_name(&tm);
+        if fs.symlink_metadata(&dir.join(&name)).is_err() {
+            return name;
+        }
+        tm = tm + Duration::seconds(1);
+    }
+}
+
+/// Parse the time from the name of a dated subdirectory.
+pub fn parse_dated_name(name: &str) -> Option<Timespec> {
+    match time::strptime(name, DATE_FORMAT) {
+        // Round-tripping rejects names with trailing characters, or unpadded numbers.
+        Ok(tm) if dated_name(&tm) == name => Some(tm.to_timespec()),
+        _ => None
+    }
+}
+
+/// List the dated subdirectories of a directory, oldest first.
+///
+/// A directory that doesn't exist yet has no dated subdirectories.
+pub fn list_dated(fs: &FileSystem, dir: &Path) -> io::Result<Vec<(Timespec, PathBuf)>> {
+    let paths = match fs.read_dir(dir) {
+        Ok(paths) => paths,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e)
+    };
+
+    let mut dated = vec![];
+    for path in paths {
+        let time = match path.file_name().and_then(|n| n.to_str()).and_then(parse_dated_name) {
+            Some(time) => time,
+            None => continue
+        };
+        if try!(fs.symlink_metadata(&path)).is_dir() {
+            dated.push((time, path));
+        }
+    }
+    dated.sort();
+    Ok(dated)
+}
+
+/// Which dated subdirectories to keep.
+///
+/// A subdirectory is removed if it is older than the maximum age, or if there are more than
+/// `keep` newer subdirectories. With neither limit set, every subdirectory is kept.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    /// The number of subdirectories to keep, including the newest.
+    pub keep: Option<usize>,
+    /// The maximum age of a subdirectory.
+    pub max_age: Option<Duration>
+}
+
+impl Retention {
+    /// Select the subdirectories to remove from a list sorted oldest first.
+    pub fn expired<'a>(&self, dated: &'a [(Timespec, PathBuf)], now: Timespec) -> Vec<&'a Path> {
+        let num_excess = match self.keep {
+            Some(keep) if dated.len() > keep => dated.len() - keep,
+            _ => 0
+        };
+
+        dated.iter().enumerate().filter(|&(i, &(time, _))| {
+            i < num_excess || self.max_age.map(|age| now - time > age).unwrap_or(false)
+        }).map(|(_, &(_, ref path))| path.as_path()).collect()
+    }
+
+    /// Remove the expired subdirectories of a directory, except for `current`, returning the
+    /// paths removed.
+    pub fn prune(&self, fs: &FileSystem, dir: &Path, current: &Path, now: Timespec)
+    -> io::Result<Vec<PathBuf>> {
+        let dated = try!(list_dated(fs, dir));
+        let mut removed = vec![];
+        for path in self.expired(&dated, now) {
+            if path == current {
+                continue;
+            }
+            try!(remove_tree(fs, path));
+            removed.push(path.to_path_buf());
+        }
+        Ok(removed)
+    }
+}
+
+// Tests
+
+#[test]
+fn test_dated_names() {
+    let tm = time::at_utc(Timespec::new(1434897000, 0));
+    assert_eq!(dated_name(&tm), "2015-06-21-143000");
+    assert_eq!(parse_dated_name("2015-06-21-143000"), Some(Timespec::new(1434897000, 0)));
+
+    for name in ["2015-06-21", "2015-06-21-143000.old", "2015-6-21-143000", "latest"].iter() {
+        assert_eq!(parse_dated_name(name), None);
+    }
+}
+
+#[test]
+fn test_unused_dated_names() {
+    use vfs::MemoryFs;
+
+    let fs = MemoryFs::new();
+    let tm = time::at_utc(Timespec::new(1434897000, 0));
+    assert_eq!(unused_dated_name(&fs, Path::new("backup"), tm), "2015-06-21-143000");
+
+    fs.add_file("backup/2015-06-21-143000/a.flac", "a");
+    fs.add_file("backup/2015-06-21-143001/b.flac", "b");
+    assert_eq!(unused_dated_name(&fs, Path::new("backup"), tm), "2015-06-21-143002");
+}
+
+#[test]
+fn test_retention() {
+    let day = 24 * 60 * 60;
+    let now = Timespec::new(10 * day, 0);
+    let dated: Vec<(Timespec, PathBuf)> = (1..10).map(|i| {
+        (Timespec::new(i * day, 0), PathBuf::from(format!("{}", i)))
+    }).collect();
+
+    let keep_all = Retention::default();
+    assert!(keep_all.expired(&dated, now).is_empty());
+
+    let keep_three = Retention { keep: Some(3), max_age: None };
+    assert_eq!(keep_three.expired(&dated, now).len(), 6);
+    assert_eq!(keep_three.expired(&dated, now)[5], Path::new("6"));
+
+    let week = Retention { keep: None, max_age: Some(Duration::days(7)) };
+    assert_eq!(week.expired(&dated, now), vec![Path::new("1"), Path::new("2")]);
+
+    let both = Retention { keep: Some(8), max_age: Some(Duration::days(7)) };
+    assert_eq!(both.expired(&dated, now), vec![Path::new("1"), Path::new("2")]);
+}
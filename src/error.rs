@@ -1,7 +1,6 @@
 use std::error;
 use std::fmt;
-
-use docopt;
+use std::io;
 
 #[derive(Debug)]
 pub struct Error {
@@ -53,8 +52,10 @@ impl fmt::Display for Error {
     }
 }
 
-impl From<docopt::Error> for Error {
-    fn from(err: docopt::Error) -> Error {
-        Error::new("docopt parsing error").with_cause(box err as Box<error::Error>)
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::new("I/O error")
+        .with_detail(format!("I/O error: {}", err))
+        .with_cause(box err as Box<error::Error>)
     }
 }
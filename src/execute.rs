@@ -0,0 +1,345 @@
+//! execute.rs, part of Skarn.
+//!
+//! This module carries out the copy and delete operations planned by `sync`.
+//!
+//! File operations go through the configured file system, apart from transform commands, which
+//! always act on the operating system's file system.
+//!
+//! If a backup directory is configured, files are moved into it rather than being deleted or
+//! overwritten. When taking a snapshot, files are copied into the new snapshot, and nothing
+//! is deleted.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeSet;
+
+use plan::Plan;
+use moves;
+use config::Config;
+use config::SymlinkMode::Follow;
+use path::{key_to_path, is_dir, is_symlink};
+use symlink::copy_link;
+use transform::transform_for;
+use preserve::preserve_metadata;
+use snapshot::link_unchanged;
+
+use self::Action::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Move,
+    Copy,
+    Delete,
+    /// Hard-link an unchanged file from the previous snapshot.
+    Link
+}
+
+/// The result of a single file operation, with the path relative to the source or destination.
+pub struct Outcome {
+    pub action: Action,
+    pub path: PathBuf,
+    pub result: io::Result<()>
+}
+
+/// Carry out a sync plan. Moves within the destination directory happen first, then paths are
+/// copied from the source directory to the destination directory, then deleted from the
+/// destination directory. Directories left empty by the deletions are removed as well.
+///
+/// When taking a snapshot, unchanged files are linked from the previous snapshot before any
+/// files are copied, and the plan's deletions are carried out by not linking those files.
+///
+/// If metadata preservation is enabled, the metadata of the directories containing the moved,
+/// copied and deleted files is set last, as changing their contents changes their timestamps.
+///
+/// A failure to move, copy or delete a single file does not stop the others from being processed.
+///
+/// Old backup directories and snapshots are pruned at the end of the sync.
+pub fn execute(plan: &Plan, config: &Config) -> Vec<Outcome> {
+    let mut outcomes = vec![];
+
+    if let Some(ref snapshot) = config.snapshot {
+        match link_unchanged(plan, snapshot, config) {
+            Ok(links) => outcomes.extend(links.into_iter()),
+            Err(e) => outcomes.push(Outcome { action: Link, path: PathBuf::new(), result: Err(e) })
+        }
+    }
+
+    for m in plan.moves.iter() {
+        let result = move_file(m, config);
+        outcomes.push(Outcome { action: Move, path: m.to.clone(), result: result });
+    }
+
+    for (relative_path, _) in plan.copies() {
+        let result = copy_file(&relative_path, config);
+        outcomes.push(Outcome { action: Copy, path: relative_path, result: result });
+    }
+
+    // Sorting and reversing the paths ensures that children are removed before their parents.
+    let mut delete_list: Vec<PathBuf> = plan.delete_paths.keys().map(|k| key_to_path(&k[..])).collect();
+    delete_list.sort();
+    delete_list.reverse();
+
+    // A new snapshot never contained the paths to delete, as they weren't linked into it.
+    if config.snapshot.is_none() {
+        let mut parents = BTreeSet::new();
+        for relative_path in delete_list {
+            parents.extend(ancestors(&relative_path).into_iter());
+            let result = delete_file(&relative_path, config);
+            outcomes.push(Outcome { action: Delete, path: relative_path, result: result });
+        }
+
+        // Directories left empty by the deletions are removed too, children first.
+        for dir in parents.iter().rev() {
+            match remove_if_empty(dir, config) {
+                Ok(false) => (),
+                result => outcomes.push(Outcome {
+                    action: Delete,
+                    path: dir.clone(),
+                    result: result.map(|_| ())
+                })
+            }
+        }
+    }
+
+    let preserve = config.preserved();
+    if !preserve.is_empty() {
+        let mut dirs = BTreeSet::new();
+        for outcome in outcomes.iter() {
+            dirs.extend(ancestors(&outcome.path).into_iter());
+        }
+
+        // Directories removed by the deletions, or absent from the source, are skipped.
+        for dir in dirs.iter().rev() {
+            let source = config.source_dir.join(dir);
+            let dest = config.dest_dir.join(dir);
+            if !is_dir(&*config.fs, &source, true) || !is_dir(&*config.fs, &dest, false) {
+                continue;
+            }
+            if let Err(e) = preserve_metadata(&*config.fs, &source, &dest, &preserve) {
+                warn!("Unable to preserve metadata of directory {}: {}", dir.display(), e);
+            }
+        }
+    }
+
+    if let Some(ref backup) = config.backup {
+        match backup.prune(&*config.fs) {
+            Ok(removed) => for dir in removed {
+                info!("Removed old backup directory: {}", dir.display());
+            },
+            Err(e) => warn!("Unable to prune old backup directories: {}", e)
+        }
+    }
+
+    if let Some(ref snapshot) = config.snapshot {
+        match snapshot.prune(&*config.fs) {
+            Ok(removed) => for dir in removed {
+                info!("Removed old snapshot: {}", dir.display());
+            },
+            Err(e) => warn!("Unable to prune old snapshots: {}", e)
+        }
+    }
+
+    outcomes
+}
+
+/// List the ancestors of a relative path, excluding the empty path.
+fn ancestors(relative_path: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    let mut path = relative_path;
+    while let Some(parent) = path.parent() {
+        if parent.as_os_str().is_empty() {
+            break;
+        }
+        dirs.push(parent.to_path_buf());
+        path = parent;
+    }
+    dirs
+}
+
+fn move_file(m: &moves::Move, config: &Config) -> io::Result<()> {
+    let from = config.dest_dir.join(&m.from);
+    let to = config.dest_dir.join(&m.to);
+
+    if let Some(parent) = to.parent() {
+        try!(config.fs.create_dir_all(parent));
+    }
+
+    config.fs.rename(&from, &to)
+}
+
+/// The path relative to the destination directory that a source file is copied to.
+pub fn dest_path(relative_path: &Path, config: &Config) -> PathBuf {
+    match transform_for(config, relative_path) {
+        Some(t) => t.dest_path(relative_path),
+        None => relative_path.to_path_buf()
+    }
+}
+
+fn copy_file(relative_path: &Path, config: &Config) -> io::Result<()> {
+    let fs = &*config.fs;
+    let source = config.source_dir.join(relative_path);
+    let transform = transform_for(config, relative_path);
+
+    let relative_dest = dest_path(relative_path, config);
+    let dest = config.dest_dir.join(&relative_dest);
+
+    if let Some(parent) = dest.parent() {
+        try!(fs.create_dir_all(parent));
+    }
+
+    // Directories in the way of a file are left for the copy to fail on, rather than backed up.
+    if let Some(ref backup) = config.backup {
+        if fs.symlink_metadata(&dest).map(|m| !m.is_dir()).unwrap_or(false) {
+            try!(backup.back_up(fs, &dest, &relative_dest));
+        }
+    }
+
+    if let Some(t) = transform {
+        try!(t.run(&source, &dest));
+    } else if config.symlink_mode != Follow && is_symlink(fs, &source) {
+        try!(copy_link(&source, &dest, config));
+    } else {
+        // Never write through an existing link in the destination.
+        if is_symlink(fs, &dest) {
+            try!(fs.remove_file(&dest));
+        }
+        try!(fs.copy(&source, &dest));
+    }
+
+    preserve_metadata(fs, &source, &dest, &config.preserved())
+}
+
+fn delete_file(relative_path: &Path, config: &Config) -> io::Result<()> {
+    let fs = &*config.fs;
+    let path = config.dest_dir.join(relative_path);
+
+    // Links are removed themselves, never the directories they point to.
+    if is_dir(fs, &path, false) {
+        // Directories are only removed once all of their contents have been deleted.
+        // A directory that still contains files which weren't marked for deletion is kept.
+        if !try!(fs.read_dir(&path)).is_empty() {
+            debug!("Keeping non-empty directory: {}", relative_path.display());
+            return Ok(());
+        }
+        fs.remove_dir(&path)
+    } else if let Some(ref backup) = config.backup {
+        backup.back_up(fs, &path, relative_path)
+    } else {
+        fs.remove_file(&path)
+    }
+}
+
+/// Remove a directory in the destination if it is empty, returning whether it was removed.
+fn remove_if_empty(relative_path: &Path, config: &Config) -> io::Result<bool> {
+    let fs = &*config.fs;
+    let path = config.dest_dir.join(relative_path);
+
+    if !is_dir(fs, &path, false) || !try!(fs.read_dir(&path)).is_empty() {
+        return Ok(false);
+    }
+    try!(fs.remove_dir(&path));
+    Ok(true)
+}
+
+// Tests
+
+#[test]
+fn test_execute() {
+    use std::io::Read;
+    use sequence_trie::SequenceTrie;
+    use vfs::MemoryFs;
+    use config::test_config;
+    use config::DeleteBehaviour::IncludedNoEquiv;
+    use sync::CopyReason::{New, Differs};
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.add_file("src/Music/b.flac", "new");
+    fs.add_file("src/Music/New/c.flac", "c");
+    fs.add_file("dst/Music/b.flac", "old");
+    fs.add_file("dst/Music/Old/d.flac", "d");
+    fs.add_file("dst/Music/Old/Album/e.flac", "e");
+    fs.add_file("dst/Music/Old/Album/f.flac", "f");
+    fs.add_file("dst/Music/Kept/g.flac", "g");
+    fs.add_file("dst/Music/Kept/h.flac", "h");
+
+    let key = |path: &str| -> Vec<String> { path.split('/').map(|s| s.to_string()).collect() };
+    let mut plan = Plan {
+        moves: vec![],
+        copy_paths: SequenceTrie::new(),
+        delete_paths: SequenceTrie::new()
+    };
+    plan.copy_paths.insert(&key("Music/a.flac")[..], New);
+    plan.copy_paths.insert(&key("Music/b.flac")[..], Differs);
+    plan.copy_paths.insert(&key("Music/New/c.flac")[..], New);
+    plan.copy_paths.insert(&key("Music/missing.flac")[..], New);
+    for path in ["Music/Old/d.flac", "Music/Old/Album/e.flac", "Music/Old/Album/f.flac",
+                 "Music/Kept", "Music/Kept/g.flac"].iter() {
+        plan.delete_paths.insert(&key(path)[..], IncludedNoEquiv);
+    }
+
+    let config = test_config(fs);
+    let outcomes = execute(&plan, &config);
+
+    // A failure doesn't stop the other operations.
+    let failures: Vec<&Path> = outcomes.iter()
+        .filter(|o| o.result.is_err())
+        .map(|o| o.path.as_path())
+        .collect();
+    assert_eq!(failures, vec![Path::new("Music/missing.flac")]);
+
+    let fs = &*config.fs;
+    let read = |path: &str| {
+        let mut contents = String::new();
+        fs.open(Path::new(path)).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    };
+    let exists = |path: &str| fs.symlink_metadata(Path::new(path)).is_ok();
+
+    assert_eq!(read("dst/Music/a.flac"), "a");
+    assert_eq!(read("dst/Music/b.flac"), "new");
+    assert_eq!(read("dst/Music/New/c.flac"), "c");
+
+    // Directories left empty are removed, but those still holding unplanned files are kept.
+    assert!(!exists("dst/Music/Old"));
+    assert!(exists("dst/Music/Kept"));
+    assert!(!exists("dst/Music/Kept/g.flac"));
+    assert!(exists("dst/Music/Kept/h.flac"));
+    assert!(exists("dst"));
+}
+
+#[test]
+fn test_repeated_sync() {
+    use vfs::MemoryFs;
+    use config::test_config;
+    use compare::{ComparisonMethod, Metadata};
+    use parser::parse_include_file;
+    use plan::plan;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.add_file("src/Music/Album/b.flac", "b");
+    fs.add_file("dst/Music/c.flac", "c");
+    fs.add_file("src/Videos/d.mkv", "d");
+    fs.add_file("dst/Videos/d.mkv", "d");
+    fs.add_file("dst/Videos/e.mkv", "e");
+    fs.set_mtime(Path::new("src/Videos/d.mkv"), 1000).unwrap();
+    fs.set_mtime(Path::new("dst/Videos/d.mkv"), 1000).unwrap();
+
+    // Comparing modification times preserves them, so the second sync has nothing to do.
+    let mut config = test_config(fs);
+    config.comparison_method = box Metadata { mtime_tolerance: 0 } as Box<ComparisonMethod>;
+    let matcher = parse_include_file("Music\nVideos", Path::new("test.ska")).unwrap();
+
+    let first = plan(&matcher, &config).unwrap();
+    assert!(!first.is_empty());
+    for outcome in execute(&first, &config) {
+        assert!(outcome.result.is_ok());
+    }
+    assert!(plan(&matcher, &config).unwrap().is_empty());
+
+    // Directories changed only by deletions get their source's timestamps back too.
+    let mtime = |path: &str| config.fs.metadata(Path::new(path)).unwrap().mtime;
+    assert_eq!(mtime("dst/Videos"), mtime("src/Videos"));
+    assert_eq!(mtime("dst/Music/Album"), mtime("src/Music/Album"));
+}
@@ -0,0 +1,425 @@
+//! gitignore.rs, part of Skarn.
+//!
+//! This module builds a matcher from the ignore rules of the Git repositories in a directory.
+//!
+//! Rules are read from each repository's `.gitignore` files, its `.git/info/exclude` file
+//! and the user's global excludes file. As in Git, the last matching rule wins, rules from
+//! deeper `.gitignore` files take precedence over shallower ones, and a path beneath an
+//! ignored directory can't be re-included. The rules of a repository don't apply inside the
+//! repositories nested within it.
+
+use std::io;
+use std::io::Read;
+use std::fmt;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use glob;
+use sequence_trie::SequenceTrie;
+
+use matcher::Matcher;
+use path::{StringComponents, is_dir};
+use vfs::{FileSystem, RealFs};
+
+static MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false
+};
+
+/// The set of ignore rules for every repository beneath a source directory.
+pub struct GitIgnore {
+    rules: Vec<Rule>,
+    /// The roots of the repositories beneath the source directory, relative to it.
+    repos: Vec<Vec<String>>
+}
+
+/// Where a rule came from, in increasing order of precedence.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+enum RuleSource {
+    GlobalExcludes,
+    InfoExclude,
+    GitIgnoreFile
+}
+
+struct Rule {
+    /// The directory that the rule applies beneath, relative to the source directory.
+    base: Vec<String>,
+    /// The line of the ignore file that the rule was parsed from.
+    line: String,
+    pattern: glob::Pattern,
+    source: RuleSource,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool
+}
+
+impl Rule {
+    /// Parse a single line of an ignore file, returning `None` for blank lines and comments.
+    fn parse(original_line: &str, base: &[String], source: RuleSource) -> Option<Rule> {
+        // Trailing spaces are ignored unless escaped with a backslash.
+        let mut line = original_line.trim_right_matches(' ').to_string();
+        if line.ends_with("\\") {
+            line.push(' ');
+        }
+
+        if line.is_empty() || line.starts_with("#") {
+            return None;
+        }
+
+        let negated = line.starts_with("!");
+        let mut pattern: &str = if negated { &line[1..] } else { &line[..] };
+
+        // Leading '#' and '!' characters can be escaped.
+        if pattern.starts_with("\\#") || pattern.starts_with("\\!") {
+            pattern = &pattern[1..];
+        }
+
+        let dir_only = pattern.ends_with("/");
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+
+        // Patterns containing a slash anywhere but the end are relative to the ignore file.
+        let anchored = pattern.contains("/");
+        if pattern.starts_with("/") {
+            pattern = &pattern[1..];
+        }
+
+        if pattern.is_empty() {
+            return None;
+        }
+
+        let pattern = match glob::Pattern::new(&unescape(pattern)) {
+            Ok(p) => p,
+            Err(_) => {
+                warn!("Ignoring invalid gitignore pattern: {}", line);
+                return None;
+            }
+        };
+
+        Some(Rule {
+            base: base.to_vec(),
+            line: original_line.to_string(),
+            pattern: pattern,
+            source: source,
+            negated: negated,
+            dir_only: dir_only,
+            anchored: anchored
+        })
+    }
+
+    fn matches(&self, path: &[String], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        if path.len() <= self.base.len() || path[..self.base.len()] != self.base[..] {
+            return false;
+        }
+
+        let rest = &path[self.base.len()..];
+
+        if self.anchored {
+            self.pattern.matches_with(&rest.connect("/"), &MATCH_OPTIONS)
+        } else {
+            self.pattern.matches_with(&rest[rest.len() - 1], &MATCH_OPTIONS)
+        }
+    }
+}
+
+/// Replace backslash escapes with glob syntax, as the glob crate has no escape character.
+///
+/// Escaped metacharacters and spaces become single-character classes, like `[*]`, and other
+/// escaped characters stand for themselves.
+fn unescape(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) if "*?[] ".contains(escaped) => {
+                    result.push('[');
+                    result.push(escaped);
+                    result.push(']');
+                },
+                Some(escaped) => result.push(escaped),
+                None => ()
+            },
+            c => result.push(c)
+        }
+    }
+    result
+}
+
+impl GitIgnore {
+    /// Read the ignore rules of every repository beneath a source directory.
+    ///
+    /// The global excludes file is always read from the operating system's file system.
+    pub fn from_source_dir(fs: &FileSystem, source_dir: &Path) -> io::Result<GitIgnore> {
+        let global_lines = match global_excludes_file() {
+            Some(path) => try!(read_lines(&RealFs, &path)),
+            None => vec![]
+        };
+
+        let mut rules = vec![];
+        let mut repos = vec![];
+
+        let mut dirs = vec![];
+        let mut stack = vec![source_dir.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            for path in try!(fs.read_dir(&dir)) {
+                // Git's own metadata is never subject to ignore rules, so it isn't walked.
+                if path.file_name().and_then(|name| name.to_str()) == Some(".git") {
+                    continue;
+                }
+                if is_dir(fs, &path, false) {
+                    stack.push(path);
+                }
+            }
+            dirs.push(dir);
+        }
+
+        for dir in dirs {
+            let base = dir.relative_from(source_dir).unwrap().string_components();
+
+            // Repository roots get the global and per-repository excludes. Submodules and
+            // worktrees have a `.git` file instead of a directory, but are still repositories.
+            let git_dir = dir.join(".git");
+            if fs.symlink_metadata(&git_dir).is_ok() {
+                repos.push(base.clone());
+                rules.extend(global_lines.iter().filter_map(|line| {
+                    Rule::parse(line, &base, RuleSource::GlobalExcludes)
+                }));
+
+                if is_dir(fs, &git_dir, false) {
+                    let info_exclude = git_dir.join("info").join("exclude");
+                    for line in try!(read_lines(fs, &info_exclude)) {
+                        rules.extend(Rule::parse(&line, &base, RuleSource::InfoExclude).into_iter());
+                    }
+                }
+            }
+
+            for line in try!(read_lines(fs, &dir.join(".gitignore"))) {
+                rules.extend(Rule::parse(&line, &base, RuleSource::GitIgnoreFile).into_iter());
+            }
+        }
+
+        // Order the rules by precedence, so that the last match wins.
+        // The sort is stable, so rules from the same file stay in order.
+        rules.sort_by(|a, b| (a.base.len(), a.source).cmp(&(b.base.len(), b.source)));
+
+        Ok(GitIgnore { rules: rules, repos: repos })
+    }
+
+    /// Determine whether a path (split into components) is ignored.
+    ///
+    /// Paths beneath an ignored directory are always ignored.
+    pub fn is_ignored(&self, path: &[String], is_dir: bool) -> bool {
+        match self.deciding_rule(path, is_dir) {
+            Some(rule) => !rule.negated,
+            None => false
+        }
+    }
+
+    /// Describe the rule that decides whether a path is ignored, if there is one.
+    pub fn describe_rule(&self, path: &[String], is_dir: bool) -> Option<String> {
+        self.deciding_rule(path, is_dir).map(|rule| rule.to_string())
+    }
+
+    /// Find the rule which ignores an ancestor of the path, or else the last rule matching it.
+    fn deciding_rule(&self, path: &[String], is_dir: bool) -> Option<&Rule> {
+        if path.iter().any(|c| c == ".git") {
+            return None;
+        }
+
+        for i in 1..path.len() {
+            match self.last_match(&path[..i], true) {
+                Some(rule) if !rule.negated => return Some(rule),
+                _ => ()
+            }
+        }
+
+        self.last_match(path, is_dir)
+    }
+
+    /// Find the last rule matching a path, among the rules of the innermost repository
+    /// containing it.
+    fn last_match(&self, path: &[String], is_dir: bool) -> Option<&Rule> {
+        // A nested repository's root directory is still part of the repository around it.
+        let repo: &[String] = self.repos.iter()
+            .filter(|repo| path.len() > repo.len() && path[..repo.len()] == repo[..])
+            .fold(&[], |innermost, repo| {
+                if repo.len() > innermost.len() { &repo[..] } else { innermost }
+            });
+
+        self.rules.iter().rev().find(|rule| {
+            rule.base.len() >= repo.len() && rule.base[..repo.len()] == repo[..] &&
+                rule.matches(path, is_dir)
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let base = PathBuf::from(self.base.connect("/"));
+        let file = match self.source {
+            RuleSource::GlobalExcludes => PathBuf::from("<global excludes>"),
+            RuleSource::InfoExclude => base.join(".git").join("info").join("exclude"),
+            RuleSource::GitIgnoreFile => base.join(".gitignore")
+        };
+        write!(f, "{}: {}", file.display(), self.line)
+    }
+}
+
+/// Create a matcher which excludes everything ignored by Git, and includes everything else.
+pub fn git_matcher(fs: &FileSystem, source_dir: &Path) -> io::Result<Matcher> {
+    let git_ignore = try!(GitIgnore::from_source_dir(fs, source_dir));
+
+    Ok(Matcher {
+        include_trie: SequenceTrie::new(),
+        exclude_trie: SequenceTrie::new(),
+        git_ignore: Some(git_ignore)
+    })
+}
+
+/// Locate the user's global excludes file, via `core.excludesFile` or the XDG default.
+fn global_excludes_file() -> Option<PathBuf> {
+    let output = Command::new("git").arg("config").arg("--path").arg("core.excludesFile").output();
+
+    if let Ok(output) = output {
+        if output.status.success() {
+            let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if !path.is_empty() {
+                return Some(PathBuf::from(path));
+            }
+        }
+    }
+
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(ref dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("git").join("ignore")),
+        _ => env::home_dir().map(|home| home.join(".config").join("git").join("ignore"))
+    }
+}
+
+/// Read the lines of an ignore file, treating a missing file as empty.
+fn read_lines(fs: &FileSystem, path: &Path) -> io::Result<Vec<String>> {
+    let mut contents = String::new();
+    match fs.open(path) {
+        Ok(mut file) => { try!(file.read_to_string(&mut contents)); },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e)
+    }
+    Ok(contents.lines().map(|l| l.to_string()).collect())
+}
+
+// Tests
+
+#[cfg(test)]
+fn components(path: &str) -> Vec<String> {
+    path.split('/').map(|s| s.to_string()).collect()
+}
+
+#[cfg(test)]
+fn git_ignore(lines: &[(&str, &str)]) -> GitIgnore {
+    let rules = lines.iter().filter_map(|&(base, line)| {
+        let base = if base.is_empty() { vec![] } else { components(base) };
+        Rule::parse(line, &base, RuleSource::GitIgnoreFile)
+    }).collect();
+    GitIgnore { rules: rules, repos: vec![vec![]] }
+}
+
+#[test]
+fn test_gitignore_negation() {
+    let ignore = git_ignore(&[("", "*.log"), ("", "!keep.log")]);
+    assert!(ignore.is_ignored(&components("src/debug.log"), false));
+    assert!(!ignore.is_ignored(&components("src/keep.log"), false));
+}
+
+#[test]
+fn test_gitignore_anchoring() {
+    let ignore = git_ignore(&[("", "/target"), ("", "doc/build")]);
+    assert!(ignore.is_ignored(&components("target"), true));
+    assert!(!ignore.is_ignored(&components("sub/target"), true));
+    assert!(ignore.is_ignored(&components("doc/build/index.html"), false));
+    assert!(!ignore.is_ignored(&components("sub/doc/build"), true));
+}
+
+#[test]
+fn test_gitignore_dir_only() {
+    let ignore = git_ignore(&[("", "build/")]);
+    assert!(ignore.is_ignored(&components("build"), true));
+    assert!(ignore.is_ignored(&components("build/output.o"), false));
+    assert!(!ignore.is_ignored(&components("build"), false));
+}
+
+#[test]
+fn test_gitignore_nested() {
+    let ignore = git_ignore(&[("", "*.tmp"), ("repo/sub", "!*.tmp"), ("repo/sub", "/local")]);
+    assert!(ignore.is_ignored(&components("repo/a.tmp"), false));
+    assert!(!ignore.is_ignored(&components("repo/sub/a.tmp"), false));
+    assert!(ignore.is_ignored(&components("repo/sub/local"), false));
+    assert!(!ignore.is_ignored(&components("repo/local"), false));
+}
+
+#[test]
+fn test_gitignore_no_reinclusion_below_ignored_dir() {
+    let ignore = git_ignore(&[("", "cache/"), ("", "!cache/important")]);
+    assert!(ignore.is_ignored(&components("cache/important"), false));
+}
+
+#[test]
+fn test_gitignore_describe_rule() {
+    let ignore = git_ignore(&[("", "build/"), ("src", "*.o")]);
+    assert_eq!(ignore.describe_rule(&components("build/lib/x.o"), false),
+               Some(".gitignore: build/".to_string()));
+    assert_eq!(ignore.describe_rule(&components("src/main.o"), false),
+               Some("src/.gitignore: *.o".to_string()));
+    assert_eq!(ignore.describe_rule(&components("src/main.c"), false), None);
+}
+
+#[test]
+fn test_gitignore_escapes() {
+    let ignore = git_ignore(&[
+        ("", "trailing\\ "), ("", "star\\*"), ("", "\\#hash"), ("", "[ab]\\?")
+    ]);
+    assert!(ignore.is_ignored(&components("trailing "), false));
+    assert!(!ignore.is_ignored(&components("trailing"), false));
+    assert!(ignore.is_ignored(&components("star*"), false));
+    assert!(!ignore.is_ignored(&components("starry"), false));
+    assert!(ignore.is_ignored(&components("#hash"), false));
+    assert!(ignore.is_ignored(&components("a?"), false));
+    assert!(!ignore.is_ignored(&components("ab"), false));
+}
+
+#[test]
+fn test_gitignore_nested_repos() {
+    let mut ignore = git_ignore(&[("", "*.log"), ("vendor/lib", "*.tmp")]);
+    ignore.repos.push(components("vendor/lib"));
+
+    assert!(ignore.is_ignored(&components("debug.log"), false));
+    assert!(!ignore.is_ignored(&components("vendor/lib/debug.log"), false));
+    assert!(ignore.is_ignored(&components("vendor/lib/a.tmp"), false));
+
+    // The nested repository's root is still subject to the outer repository's rules.
+    let mut ignore = git_ignore(&[("", "lib")]);
+    ignore.repos.push(components("lib"));
+    assert!(ignore.is_ignored(&components("lib"), true));
+}
+
+#[test]
+fn test_gitignore_submodules() {
+    use vfs::MemoryFs;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/.git/info/exclude", "");
+    fs.add_file("src/.gitignore", "*.skarn-outer");
+    fs.add_file("src/vendor/lib/.git", "gitdir: ../../.git/modules/lib");
+    fs.add_file("src/vendor/lib/a.skarn-outer", "");
+
+    // A `.git` file marks a repository boundary, just like a `.git` directory.
+    let ignore = GitIgnore::from_source_dir(&fs, Path::new("src")).unwrap();
+    assert!(ignore.is_ignored(&components("a.skarn-outer"), false));
+    assert!(!ignore.is_ignored(&components("vendor/lib/a.skarn-outer"), false));
+}
@@ -0,0 +1,158 @@
+//! hash.rs, part of Skarn.
+//!
+//! This module computes SHA-256 hashes of files, and caches them between runs.
+//!
+//! Cached hashes are keyed by canonical path, and are only reused if the file's size,
+//! modification time and inode number are unchanged. Entries for files that weren't hashed
+//! during a run, such as files that have since been deleted, are dropped when the cache is saved.
+
+use std::io;
+use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rustc_serialize::json;
+
+use vfs::FileSystem;
+
+/// Compute the SHA-256 hash of a file's contents, as a hex string.
+pub fn hash_file(fs: &FileSystem, path: &Path) -> io::Result<String> {
+    let mut file = try!(fs.open(path));
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 65536];
+
+    loop {
+        let num_bytes = try!(file.read(&mut buffer));
+        if num_bytes == 0 {
+            break;
+        }
+        hasher.input(&buffer[..num_bytes]);
+    }
+
+    Ok(hasher.result_str())
+}
+
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Eq, Debug)]
+struct CacheEntry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    inode: u64,
+    hash: String
+}
+
+/// A cache of file hashes, optionally persisted to a file.
+pub struct HashCache {
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    /// The keys of the files hashed during this run, from the cache or otherwise.
+    seen: Mutex<HashSet<String>>,
+    modified: AtomicBool
+}
+
+impl HashCache {
+    /// Create a hash cache, loading existing entries from the cache file if it exists.
+    ///
+    /// If no path is given, hashes are cached in memory only.
+    pub fn open(path: Option<PathBuf>) -> io::Result<HashCache> {
+        let mut entries = HashMap::new();
+
+        if let Some(ref path) = path {
+            match File::open(path) {
+                Ok(mut file) => {
+                    let mut contents = String::new();
+                    try!(file.read_to_string(&mut contents));
+                    entries = try!(json::decode(&contents).map_err(|e| {
+                        io::Error::new(io::ErrorKind::InvalidData, format!("invalid hash cache: {}", e))
+                    }));
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e)
+            }
+        }
+
+        Ok(HashCache {
+            path: path,
+            entries: Mutex::new(entries),
+            seen: Mutex::new(HashSet::new()),
+            modified: AtomicBool::new(false)
+        })
+    }
+
+    /// Get the hash of a file, from the cache if it is up to date, or by hashing it otherwise.
+    pub fn hash(&self, fs: &FileSystem, path: &Path) -> io::Result<String> {
+        let key = try!(fs.canonicalize(path)).to_string_lossy().into_owned();
+        let metadata = try!(fs.metadata(path));
+        self.seen.lock().unwrap().insert(key.clone());
+
+        if let Some(entry) = self.entries.lock().unwrap().get(&key) {
+            if entry.size == metadata.len &&
+               entry.mtime == metadata.mtime &&
+               entry.mtime_nsec == metadata.mtime_nsec &&
+               entry.inode == metadata.ino {
+                return Ok(entry.hash.clone());
+            }
+        }
+
+        debug!("Hashing: {}", path.display());
+        let hash = try!(hash_file(fs, path));
+
+        let entry = CacheEntry {
+            size: metadata.len,
+            mtime: metadata.mtime,
+            mtime_nsec: metadata.mtime_nsec,
+            inode: metadata.ino,
+            hash: hash.clone()
+        };
+        self.entries.lock().unwrap().insert(key, entry);
+        self.modified.store(true, Ordering::Relaxed);
+
+        Ok(hash)
+    }
+
+    /// Write the cache back to its file, if it has one and has been modified, dropping the
+    /// entries for files that weren't hashed during this run.
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(())
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        let unseen: Vec<String> = {
+            let seen = self.seen.lock().unwrap();
+            entries.keys().filter(|key| !seen.contains(*key)).cloned().collect()
+        };
+        for key in unseen.iter() {
+            entries.remove(key);
+        }
+
+        if !self.modified.load(Ordering::Relaxed) && unseen.is_empty() {
+            return Ok(());
+        }
+
+        let contents = try!(json::encode(&*entries).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("unable to encode hash cache: {}", e))
+        }));
+
+        // Write to a temporary file first, so that an interrupted write can't corrupt the cache.
+        // Its name extends the cache's own, so that the two can never be the same file.
+        let mut temp_name = path.file_name().unwrap_or(OsStr::new("hash-cache")).to_os_string();
+        temp_name.push(".partial");
+        let temp_path = path.with_file_name(temp_name);
+        {
+            let mut file = try!(File::create(&temp_path));
+            try!(file.write_all(contents.as_bytes()));
+        }
+        try!(fs::rename(&temp_path, path));
+
+        self.modified.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
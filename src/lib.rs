@@ -0,0 +1,106 @@
+//! Skarn is a selective file sync library.
+//!
+//! Syncing happens in three stages:
+//!
+//! 1. An include file is parsed into a `Matcher` (or one is built from Git ignore rules), which
+//!    classifies paths as included or excluded.
+//! 2. The source and destination directories are compared to compute a `Plan` of the moves,
+//!    copies and deletions required.
+//! 3. The plan is checked for excessive deletions, and executed, producing an `Outcome` for
+//!    each file operation.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! # fn sync(config: &skarn::Config) -> std::io::Result<()> {
+//! let matcher = skarn::parse_include_path(Path::new("music.ska")).unwrap();
+//! let plan = try!(skarn::plan(&matcher, config));
+//! try!(config.comparison_method.finish());
+//! skarn::check_plan(&plan, &matcher, config).unwrap();
+//!
+//! for outcome in skarn::execute(&plan, config) {
+//!     if let Err(e) = outcome.result {
+//!         println!("{}: {}", outcome.path.display(), e);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The behaviour of each stage is controlled by a `Config`. The public modules contain the types
+//! used to build one, and the bidirectional sync; everything else is reached through the
+//! functions and types exported here.
+
+#![feature(box_syntax)]
+#![feature(plugin)]
+#![feature(path_relative_from)]
+#![feature(path_ext)]
+#![feature(fs_walk)]
+
+#![plugin(regex_macros)]
+
+// Rust-lang libraries.
+extern crate regex;
+extern crate rustc_serialize;
+extern crate glob;
+extern crate crypto;
+extern crate libc;
+extern crate filetime;
+#[macro_use] extern crate log;
+extern crate time;
+
+// Third-party libraries.
+extern crate sequence_trie;
+extern crate inotify;
+extern crate crossbeam;
+
+pub use config::Config;
+pub use parser::{parse_include_path, parse_include_file, ParseError};
+pub use gitignore::git_matcher;
+pub use matcher::{Matcher, Class, Explanation};
+pub use plan::{Plan, plan};
+pub use sync::{CopyReason, CopyTrie, DeleteTrie};
+pub use moves::Move;
+pub use safety::{check_plan, check_bisync};
+pub use execute::{execute, Outcome, Action};
+pub use report::Report;
+pub use watch::watch;
+pub use error::Error;
+
+// Configuration, and the types used to build a `Config`.
+pub mod config;
+pub mod compare;
+pub mod hash;
+pub mod vfs;
+pub mod transform;
+pub mod backup;
+pub mod snapshot;
+pub mod dated;
+
+// File system manipulation.
+mod path;
+mod parallel;
+mod symlink;
+mod preserve;
+
+// Include file parsing.
+mod parser;
+mod pattern;
+mod casefold;
+mod gitignore;
+
+// Selection algorithm logic.
+mod matcher;
+mod sync;
+mod moves;
+mod plan;
+mod safety;
+mod execute;
+mod report;
+mod watch;
+
+// Bidirectional syncs, which have their own plans and state.
+pub mod bisync;
+
+mod error;
+//pub mod debug;
@@ -1,48 +1,40 @@
+//! The Skarn command-line interface.
+
 #![feature(box_syntax)]
 #![feature(plugin)]
 #![feature(path_relative_from)]
-#![feature(path_ext)]
-#![feature(fs_walk)]
 
-#![plugin(docopt_macros, regex_macros)]
+#![plugin(docopt_macros)]
 
 // Rust-lang libraries.
-extern crate regex;
 extern crate rustc_serialize;
-extern crate glob;
 #[macro_use] extern crate log;
 extern crate env_logger;
 
 // Third-party libraries.
-extern crate sequence_trie;
 extern crate docopt;
+extern crate time;
 
-use std::fs::File;
-use std::io::Read;
-use std::error::Error as StdError;
-
-use config::PatternSource::IncludeFile;
-use parser::parse_include_file;
-use sync::sync;
+extern crate skarn;
 
-// Configuration and argument parsing.
-pub mod arg_parser;
-pub mod config;
-
-// File system manipulation.
-pub mod compare;
-pub mod path;
+use std::error::Error as StdError;
+use std::io;
+use std::process;
+use std::io::Write;
+use std::path::Path;
 
-// Include file parsing.
-pub mod parser;
-pub mod pattern;
+use skarn::{Config, Matcher, Outcome, parse_include_path, git_matcher, plan, check_plan, execute};
+use skarn::{check_bisync, watch};
+use skarn::bisync::{State, plan_bisync, execute_bisync};
+use skarn::config::PatternSource::{IncludeFile, Git};
+use skarn::config::SymlinkMode::Follow;
+use skarn::config::OutputFormat::{Itemized, Json, Ndjson};
+use skarn::Action::{Move, Copy, Delete, Link};
 
-// Selection algorithm logic.
-pub mod matcher;
-pub mod sync;
+use output::{print_itemized, print_json, print_ndjson};
 
-pub mod error;
-//pub mod debug;
+mod arg_parser;
+mod output;
 
 fn main() {
     // Set up logging.
@@ -59,31 +51,40 @@ fn main() {
         }
     };
 
-    // Read the include file.
-    let mut include_file = String::new();
-    match config.pattern_type {
-        IncludeFile(ref filename) => {
-            File::open(filename).unwrap().read_to_string(&mut include_file).ok();
+    let matcher = match config.pattern_type {
+        IncludeFile(ref filename) => match parse_include_path(filename) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Error in include file.");
+                println!("{}", e);
+                return;
+            }
         },
-        _ => unimplemented!()
-    };
-
-    // Parse the include file.
-    let matcher = match parse_include_file(&include_file) {
-        Ok(x) => x,
-        Err(e) => {
-            println!("Syntax error in include file.");
-            println!("{:?}", e);
-            return;
+        Git => match git_matcher(&*config.fs, &config.source_dir) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Error reading Git ignore rules: {}", e);
+                return;
+            }
         }
     };
 
+    if let Some(ref path) = config.explain {
+        explain(&matcher, path, &config);
+        return;
+    }
+
+    if let Some(ref state_path) = config.bisync_state {
+        bisync(&matcher, state_path, &config);
+        return;
+    }
+
     debug!("Include Tree:");
     debug!("{:?}", matcher.include_trie);
     debug!("Exclude Tree:");
     debug!("{:?}", matcher.exclude_trie);
 
-    let (copy_paths, delete_paths) = match sync(&matcher, &mut config) {
+    let plan = match plan(&matcher, &config) {
         Ok(x) => x,
         Err(e) => {
             println!("{:?}", e);
@@ -91,13 +92,170 @@ fn main() {
         }
     };
 
+    if let Err(e) = config.comparison_method.finish() {
+        println!("Error saving comparison state: {}", e);
+    }
+
     debug!("Paths to copy:");
-    for path in copy_paths.keys() {
+    for path in plan.copy_paths.keys() {
         debug!("{:?}", path);
     }
 
     debug!("Would Delete:");
-    for path in delete_paths.keys() {
+    for path in plan.delete_paths.keys() {
         debug!("{:?}", path);
     }
+
+    let safety_check = check_plan(&plan, &matcher, &config);
+
+    if config.dry_run {
+        let printed = match config.output_format {
+            Itemized => Ok(print_itemized(&plan, &config)),
+            Json => print_json(&plan, &config),
+            Ndjson => print_ndjson(&plan, &config)
+        };
+        if let Err(e) = printed {
+            let _ = writeln!(io::stderr(), "Error reporting the plan: {}", e);
+            process::exit(1);
+        }
+        if let Err(e) = safety_check {
+            // Keep standard output parseable for the JSON formats.
+            if config.output_format == Itemized {
+                println!("Note: this sync would be aborted: {}", e);
+            } else {
+                let _ = writeln!(io::stderr(), "Note: this sync would be aborted: {}", e);
+            }
+        }
+        return;
+    }
+
+    if let Err(e) = safety_check {
+        abort(e);
+    }
+
+    print_outcomes(&execute(&plan, &config));
+
+    if config.watch {
+        info!("Watching {} for changes", config.source_dir.display());
+        if let Err(e) = watch(&matcher, &mut config, |outcomes| print_outcomes(&outcomes)) {
+            println!("Error watching for changes: {}", e);
+        }
+    }
+}
+
+/// Report that a sync failed its safety checks, and exit with a failure status so that scripts
+/// can tell that nothing was synced.
+fn abort(e: skarn::Error) -> ! {
+    let _ = writeln!(io::stderr(), "Aborting sync, nothing has been changed: {}", e);
+    let _ = writeln!(io::stderr(), "Use --force to sync anyway.");
+    process::exit(1);
+}
+
+/// Print the outcome of each file operation, and the number that failed.
+fn print_outcomes(outcomes: &[Outcome]) {
+    let mut num_failures = 0;
+    for outcome in outcomes.iter() {
+        let verb = match outcome.action {
+            Move => "move",
+            Copy => "copy",
+            Delete => "delete",
+            Link => "link"
+        };
+        match outcome.result {
+            // Every unchanged file in a snapshot is linked, so only failures are worth printing.
+            Ok(()) if outcome.action == Link => (),
+            Ok(()) => println!("{}: {}", verb, outcome.path.display()),
+            Err(ref e) => {
+                println!("failed to {}: {} ({})", verb, outcome.path.display(), e);
+                num_failures += 1;
+            }
+        }
+    }
+
+    if num_failures > 0 {
+        println!("{} of {} operations failed.", num_failures, outcomes.len());
+    }
+}
+
+/// Print an explanation of how a path is classified.
+///
+/// The path may be relative to the source directory, or include it.
+fn explain(matcher: &Matcher, path: &Path, config: &Config) {
+    let relative_path = path.relative_from(&config.source_dir).unwrap_or(path);
+    let full_path = config.source_dir.join(relative_path);
+    let metadata = if config.symlink_mode == Follow {
+        config.fs.metadata(&full_path)
+    } else {
+        config.fs.symlink_metadata(&full_path)
+    };
+    let is_dir = metadata.map(|m| m.is_dir()).unwrap_or(false);
+
+    println!("{}", matcher.explain(relative_path, is_dir, config.include_by_default));
+}
+
+/// Sync changes in both directions, printing each change made.
+fn bisync(matcher: &Matcher, state_path: &Path, config: &Config) {
+    let fs = &*config.fs;
+
+    let mut state = match State::load(fs, state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Error reading state file: {}", e);
+            return;
+        }
+    };
+
+    let plan = match plan_bisync(matcher, config, &state) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{:?}", e);
+            return;
+        }
+    };
+
+    let safety_check = check_bisync(&plan, matcher, config);
+
+    if config.dry_run {
+        for &(ref path, change) in plan.changes.iter() {
+            println!("{:<25} {}", change.name(), path.display());
+        }
+        if !plan.conflicts.is_empty() {
+            println!("Conflicts:");
+            for &(ref path, resolution) in plan.conflicts.iter() {
+                let name = resolution.map(|c| c.name()).unwrap_or("skip");
+                println!("{:<25} {}", name, path.display());
+            }
+        }
+        if let Err(e) = safety_check {
+            println!("Note: this sync would be aborted: {}", e);
+        }
+        return;
+    }
+
+    if let Err(e) = safety_check {
+        abort(e);
+    }
+
+    let outcomes = execute_bisync(&plan, config, &mut state);
+
+    let mut num_failures = 0;
+    for outcome in outcomes.iter() {
+        let name = outcome.change.map(|c| c.name()).unwrap_or("skip");
+        let prefix = if outcome.conflict { "conflict, " } else { "" };
+        match outcome.result {
+            Ok(()) => println!("{}{}: {}", prefix, name, outcome.path.display()),
+            Err(ref e) => {
+                println!("{}failed to {}: {} ({})", prefix, name, outcome.path.display(), e);
+                num_failures += 1;
+            }
+        }
+    }
+
+    if let Err(e) = state.save(fs) {
+        println!("Error saving state file: {}", e);
+    }
+
+    if num_failures > 0 {
+        println!("{} of {} operations failed.", num_failures, outcomes.len());
+    }
 }
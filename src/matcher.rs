@@ -1,27 +1,35 @@
 use std::io;
+use std::fmt;
+use std::cmp::Ordering;
 use std::path::Path;
-use std::fs::{read_dir, walk_dir, PathExt};
 
 use sequence_trie::SequenceTrie;
 
 use pattern::Pattern;
-use path::StringComponents;
+use parser::Origin;
+use gitignore::GitIgnore;
+use path::{StringComponents, is_dir, enter_dir};
+use parallel::traverse;
+use vfs::FileSystem;
 
 use self::Class::*;
 
 pub struct Matcher {
     pub include_trie: PatternTrie,
-    pub exclude_trie: PatternTrie
+    pub exclude_trie: PatternTrie,
+    /// Git ignore rules, which exclude paths regardless of the pattern tries.
+    pub git_ignore: Option<GitIgnore>
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Class {
     Included,
     Excluded,
     Both
 }
 
-pub type PatternTrie = SequenceTrie<Pattern, ()>;
+/// A trie of patterns, with the include file lines of the patterns ending at each node.
+pub type PatternTrie = SequenceTrie<Pattern, Vec<Origin>>;
 pub type PathTrie = SequenceTrie<String, ()>;
 
 impl Matcher {
@@ -31,33 +39,65 @@ impl Matcher {
     /// include trie than they do in the exclude trie.
     ///
     /// Paths which match trails of equal length in both tries are classified as `Both`.
-    pub fn classify(&self, path: &Path) -> Class {
+    ///
+    /// A `**` pattern matches zero or more path components. The components it absorbs only count
+    /// towards the length of a trail once a later pattern in the trail matches, or if the `**`
+    /// is the last pattern in the trail.
+    ///
+    /// Paths ignored by the Git ignore rules (if any) are always excluded.
+    /// Whether the path is a directory matters only for directory-specific ignore rules.
+    pub fn classify(&self, path: &Path, is_dir: bool) -> Class {
         // Split the path into its components.
         let path_components: Vec<String> = path.string_components();
 
-        // Explore down the tree in layers, as there could be multiple matches at each level.
-        let mut matching_include_nodes = vec![&self.include_trie];
-        let mut matching_exclude_nodes = vec![&self.exclude_trie];
-
-        for component in path_components {
-            // Expand the layers of include and exclude nodes.
-            matching_include_nodes = new_matching_nodes(&component, matching_include_nodes);
-            matching_exclude_nodes = new_matching_nodes(&component, matching_exclude_nodes);
-
-            match (matching_include_nodes.is_empty(), matching_exclude_nodes.is_empty()) {
-                // If both pattern paths are exhausted, it's a tie.
-                (true, true) => return Both,
-                // If only the inclusion path is exhausted, the path is excluded.
-                (true, false) => return Excluded,
-                // If only the exclusion pattern path is exhausted, the path is included.
-                (false, true) => return Included,
-                // If neither layer is empty, continue.
-                (false, false) => ()
+        if let Some(ref git_ignore) = self.git_ignore {
+            if git_ignore.is_ignored(&path_components, is_dir) {
+                return Excluded;
             }
         }
 
-        // If the path runs out before both pattern tries, it is both included and excluded!
-        Both
+        let include = match_trail(&self.include_trie, &path_components);
+        let exclude = match_trail(&self.exclude_trie, &path_components);
+
+        match include.length().cmp(&exclude.length()) {
+            // If both pattern paths are exhausted at the same point, it's a tie.
+            // If the path runs out before both pattern tries, it is both included and excluded!
+            Ordering::Equal => Both,
+            // A directory can't be classified unambiguously while a `**` or whole path pattern
+            // from the losing trie could still match paths beneath it.
+            Ordering::Greater if is_dir && exclude.pending => Both,
+            Ordering::Less if is_dir && include.pending => Both,
+            // If only the exclusion pattern path is exhausted, the path is included.
+            Ordering::Greater => Included,
+            // If only the inclusion path is exhausted, the path is excluded.
+            Ordering::Less => Excluded
+        }
+    }
+
+    /// Explain how a path is classified, by reporting the patterns matched at each depth.
+    ///
+    /// The `include_by_default` parameter is used to describe how ties are broken.
+    pub fn explain(&self, path: &Path, is_dir: bool, include_by_default: bool) -> Explanation {
+        let path_components: Vec<String> = path.string_components();
+
+        let (git_ignored, git_ignore_rule) = match self.git_ignore {
+            Some(ref git_ignore) => (
+                git_ignore.is_ignored(&path_components, is_dir),
+                git_ignore.describe_rule(&path_components, is_dir)
+            ),
+            None => (false, None)
+        };
+
+        Explanation {
+            include: explain_trail(&self.include_trie, &path_components),
+            exclude: explain_trail(&self.exclude_trie, &path_components),
+            class: self.classify(path, is_dir),
+            path: path_components,
+            is_dir: is_dir,
+            git_ignored: git_ignored,
+            git_ignore_rule: git_ignore_rule,
+            include_by_default: include_by_default
+        }
     }
 
     /// Recursively classify every file under a given directory.
@@ -65,80 +105,489 @@ impl Matcher {
     /// For files that are unclassifiable, the `include_by_default` parameter determines
     /// whether the files should be included or excluded.
     ///
+    /// Symlinks to directories beneath the root are only descended into if `follow_links` is
+    /// true, otherwise they are classified like regular files.
+    ///
+    /// Directories are listed on the given number of threads.
+    ///
     /// Returns two tries of paths, for included and excluded files respectively.
     /// The paths in both tries are relative to the root.
-    pub fn classify_recursive(&self, root: &Path, include_by_default: bool)
+    pub fn classify_recursive(&self, fs: &FileSystem, root: &Path, include_by_default: bool,
+                              follow_links: bool, threads: usize)
     -> io::Result<(PathTrie, PathTrie)>
     {
-        let mut include_trie: PathTrie = SequenceTrie::new();
-        let mut exclude_trie: PathTrie = SequenceTrie::new();
-
-        let mut stack = vec![root.to_path_buf()];
-
-        while let Some(path) = stack.pop() {
-            let relative_path = path.relative_from(root).unwrap();
-
-            let trie = match self.classify(&relative_path) {
-                Included => &mut include_trie,
-                Excluded => &mut exclude_trie,
-                Both => {
-                    // Directories classified as both need further exploration.
-                    if path.is_dir() {
-                        let children = try!(read_dir(&path));
-                        for entry in children.into_iter() {
-                            let child = try!(entry).path();
-                            stack.push(child);
-                        }
-                        continue;
-                    }
+        // Each path to visit is paired with the class of its closest unambiguously matched
+        // ancestor directory, if any, as files beneath such a directory cannot be classified
+        // differently from it. The directories above it are kept to detect symlink cycles.
+        let root_item = (root.to_path_buf(), None, vec![]);
+        let files = try!(traverse(vec![root_item], threads, |(path, class, ancestors)| {
+            // The root is always followed, so that a link to the source directory is synced.
+            let path_is_dir = is_dir(fs, &path, follow_links || path == root);
+            let relative_path = path.relative_from(root).unwrap().to_path_buf();
 
+            if !path_is_dir {
+                let included = match class {
+                    Some(included) => included,
                     // Files need to be discriminated by the tie-breaker.
-                    if include_by_default {
-                        &mut include_trie
-                    } else {
-                        &mut exclude_trie
+                    None => match self.classify(&relative_path, false) {
+                        Included => true,
+                        Excluded => false,
+                        Both => include_by_default
                     }
+                };
+                return Ok((vec![], vec![(included, relative_path.string_components())]));
+            }
+
+            // Directories classified as both need further exploration.
+            let class = match class {
+                Some(included) => Some(included),
+                None => match self.classify(&relative_path, true) {
+                    Included => Some(true),
+                    Excluded => Some(false),
+                    Both => None
                 }
             };
 
-            // If the path is a directory with an unambiguous match, add all files recursively.
-            // Files beneath the directory cannot be classified different from the directory.
-            if path.is_dir() {
-                let path_dir_walk = try!(walk_dir(&path));
+            let ancestors = match try!(enter_dir(fs, &path, ancestors, follow_links)) {
+                Some(ancestors) => ancestors,
+                None => return Ok((vec![], vec![]))
+            };
+            let children = try!(fs.read_dir(&path)).into_iter().map(|child| {
+                (child, class, ancestors.clone())
+            }).collect();
+            Ok((children, vec![]))
+        }));
 
-                for entry in path_dir_walk {
-                    let child_path = try!(entry).path();
-                    // Avoid unneccessary insert operations for child directories.
-                    if child_path.is_dir() {
-                        continue;
-                    }
+        let mut include_trie: PathTrie = SequenceTrie::new();
+        let mut exclude_trie: PathTrie = SequenceTrie::new();
+
+        for (included, path_key) in files {
+            let trie = if included { &mut include_trie } else { &mut exclude_trie };
+            trie.insert(&path_key[..], ());
+        }
+
+        Ok((include_trie, exclude_trie))
+    }
+}
+
+/// A report of how a path was classified, for debugging include files.
+pub struct Explanation {
+    pub path: Vec<String>,
+    pub is_dir: bool,
+    /// Whether the path is ignored by Git, which excludes it regardless of the pattern tries.
+    pub git_ignored: bool,
+    /// The Git ignore rule deciding whether the path is ignored, if any.
+    pub git_ignore_rule: Option<String>,
+    pub include: TrailReport,
+    pub exclude: TrailReport,
+    pub class: Class,
+    pub include_by_default: bool
+}
+
+/// The patterns of a single trie matched by a path.
+pub struct TrailReport {
+    /// The patterns matched at each depth, for each component up to where the trie ran out.
+    pub steps: Vec<Vec<PatternMatch>>,
+    /// The index of the first component that no pattern in the trie matched, if any.
+    pub ran_out_at: Option<usize>,
+    /// The number of leading components counted towards the length of the trail.
+    pub depth: usize,
+    /// Whether the trail matched the entire path.
+    pub complete: bool,
+    /// Whether a `**` or whole path pattern could still match paths beneath this one.
+    pub pending: bool
+}
+
+/// A pattern which matched a path component.
+pub struct PatternMatch {
+    pub pattern: String,
+    /// Whether the match counts towards the length of the trail (`**` matches might not).
+    pub counted: bool,
+    /// The include file lines of the patterns that pass through this one.
+    pub origins: Vec<Origin>
+}
+
+impl Explanation {
+    /// Whether the path is included, after breaking ties, or `None` for a directory
+    /// whose contents are classified individually.
+    pub fn is_included(&self) -> Option<bool> {
+        match self.class {
+            Included => Some(true),
+            Excluded => Some(false),
+            Both if self.is_dir => None,
+            Both => Some(self.include_by_default)
+        }
+    }
+
+    fn decision_reason(&self) -> &'static str {
+        if self.git_ignored {
+            return "the path is ignored by Git";
+        }
+
+        match self.class {
+            Included => "the include patterns match more of the path",
+            Excluded => "the exclude patterns match more of the path",
+            Both if self.is_dir && self.include.length() != self.exclude.length() =>
+                "a `**` or whole path pattern could still match paths beneath it",
+            Both if self.is_dir => "it is a tie, so its contents are classified individually",
+            Both if self.include_by_default => "it is a tie, and paths are included by default",
+            Both => "it is a tie, and paths are excluded by default"
+        }
+    }
+}
+
+impl TrailReport {
+    fn length(&self) -> (bool, usize) {
+        (self.complete, self.depth)
+    }
+
+    fn fmt_trail(&self, f: &mut fmt::Formatter, name: &str, path: &[String])
+    -> Result<(), fmt::Error>
+    {
+        try!(writeln!(f, "{} patterns:", name));
 
-                    let path_key = child_path.relative_from(root).unwrap().string_components();
-                    trie.insert(&path_key[..], ());
+        for (component, matches) in path.iter().zip(self.steps.iter()) {
+            if matches.is_empty() {
+                try!(writeln!(f, "    {}: no match", component));
+            }
+            for m in matches.iter() {
+                let counted = if m.counted { "" } else { " (not counted)" };
+                try!(writeln!(f, "    {}: matched by {}{}", component, m.pattern, counted));
+                for origin in m.origins.iter() {
+                    try!(writeln!(f, "        from {}", origin));
                 }
             }
-            // If the path corresponds to a regular file, add it to its respective trie.
-            else {
-                let path_key = relative_path.string_components();
-                trie.insert(&path_key[..], ());
+        }
+
+        if let Some(i) = self.ran_out_at {
+            try!(writeln!(f, "    {} patterns ran out at '{}'", name, path[i]));
+        }
+
+        let extent = if self.complete { "the whole path".to_string() } else {
+            format!("{} of {} components", self.depth, path.len())
+        };
+        writeln!(f, "    {} trail length: {}", name, extent)
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let kind = if self.is_dir { "directory" } else { "file" };
+        try!(writeln!(f, "Path: {} ({})", self.path.connect("/"), kind));
+
+        if let Some(ref rule) = self.git_ignore_rule {
+            try!(writeln!(f, "Git ignore rule: {}", rule));
+        }
+
+        try!(self.include.fmt_trail(f, "Include", &self.path));
+        try!(self.exclude.fmt_trail(f, "Exclude", &self.path));
+
+        let decision = match self.is_included() {
+            Some(true) => "included",
+            Some(false) => "excluded",
+            None => "undecided"
+        };
+        write!(f, "Decision: {}, as {}", decision, self.decision_reason())
+    }
+}
+
+/// The extent to which a path matches a trail of patterns in a single trie.
+#[derive(Debug, Clone, Copy)]
+struct Trail {
+    /// Whether the entire path matched.
+    complete: bool,
+    /// The number of leading path components matched.
+    depth: usize,
+    /// Whether a `**` pattern could still match components beyond the end of the path.
+    pending: bool
+}
+
+impl Trail {
+    /// The length of the trail, for comparison. Trails matching the entire path are longest.
+    fn length(&self) -> (bool, usize) {
+        (self.complete, self.depth)
+    }
+}
+
+/// A node of a pattern trie, reached by matching some of the components of a path.
+#[derive(Clone, Copy)]
+struct Position<'a> {
+    node: &'a PatternTrie,
+    /// The pattern matched to reach the node, which is `None` for the root.
+    pattern: Option<&'a Pattern>,
+    /// Whether the node was reached via a `**` pattern, which may absorb further components.
+    recursive: bool
+}
+
+impl<'a> Position<'a> {
+    /// Whether the position counts towards the length of a trail.
+    ///
+    /// This is the case for nodes reached by matching the current component with a pattern
+    /// other than `**`, and for `**` patterns that end a trail.
+    fn is_specific(&self) -> bool {
+        !self.recursive || self.node.value.is_some()
+    }
+}
+
+fn match_trail(trie: &PatternTrie, path_components: &[String]) -> Trail {
+    match_trail_with(trie, path_components, |_| {})
+}
+
+/// Match a path against a trie, passing the positions reached at each component to `observe`.
+fn match_trail_with<'a, F>(trie: &'a PatternTrie, path_components: &[String], mut observe: F) -> Trail
+where F: FnMut(&[Position<'a>]) {
+    // Whole path patterns are only found at the root of the trie.
+    let path_patterns: Vec<(&'a Pattern, &'a PatternTrie)> = trie.children.iter()
+        .filter(|&(pattern, _)| pattern.is_path_pattern())
+        .collect();
+
+    // Explore down the tree in layers, as there could be multiple matches at each level.
+    let mut positions = expand_recursive(vec![Position { node: trie, pattern: None, recursive: false }]);
+    let mut depth = 0;
+    let mut prefix = String::new();
+
+    for (i, component) in path_components.iter().enumerate() {
+        positions = new_matching_nodes(component, positions);
+
+        // Match whole path patterns against the path up to and including this component.
+        if i > 0 {
+            prefix.push('/');
+        }
+        prefix.push_str(component);
+
+        for &(pattern, child) in path_patterns.iter() {
+            if pattern.matches(&prefix) {
+                positions.push(Position { node: child, pattern: Some(pattern), recursive: false });
             }
         }
 
-        Ok((include_trie, exclude_trie))
+        observe(&positions);
+
+        if positions.is_empty() && path_patterns.is_empty() {
+            return Trail { complete: false, depth: depth, pending: false };
+        }
+
+        if positions.iter().any(|p| p.is_specific()) {
+            depth = i + 1;
+        }
+    }
+
+    // Whole path patterns could match paths beneath this one, unless their literal prefix
+    // rules it out.
+    Trail {
+        complete: depth == path_components.len(),
+        depth: depth,
+        pending: positions.iter().any(|p| p.recursive) ||
+                 path_patterns.iter().any(|&(pattern, _)| pattern.could_match_beneath(&prefix))
     }
 }
 
-fn new_matching_nodes<'a>(component: &str, matching_nodes: Vec<&'a PatternTrie>)
--> Vec<&'a PatternTrie>
-{
-    let mut new_matching_nodes = vec![];
+fn new_matching_nodes<'a>(component: &str, positions: Vec<Position<'a>>) -> Vec<Position<'a>> {
+    let mut new_positions = vec![];
 
-    for &node in matching_nodes.iter() {
-        for (child_pattern, child) in node.children.iter() {
-            if child_pattern.matches(component) {
-                new_matching_nodes.push(child);
+    for position in positions.iter() {
+        // A `**` pattern absorbs the component, leaving the position unchanged.
+        if position.recursive {
+            new_positions.push(*position);
+        }
+
+        for (child_pattern, child) in position.node.children.iter() {
+            if !child_pattern.is_recursive() && !child_pattern.is_path_pattern() &&
+               child_pattern.matches(component) {
+                new_positions.push(Position { node: child, pattern: Some(child_pattern), recursive: false });
             }
         }
     }
-    new_matching_nodes
+    expand_recursive(new_positions)
+}
+
+/// Add the positions reachable by matching `**` patterns against zero components.
+fn expand_recursive<'a>(positions: Vec<Position<'a>>) -> Vec<Position<'a>> {
+    let mut expanded: Vec<Position<'a>> = vec![];
+    let mut stack = positions;
+
+    while let Some(position) = stack.pop() {
+        // Skip duplicate positions, which would otherwise multiply with each `**` pattern.
+        let is_duplicate = expanded.iter().any(|p| {
+            p.recursive == position.recursive &&
+            p.node as *const PatternTrie == position.node as *const PatternTrie
+        });
+        if is_duplicate {
+            continue;
+        }
+
+        for (child_pattern, child) in position.node.children.iter() {
+            if child_pattern.is_recursive() {
+                stack.push(Position { node: child, pattern: Some(child_pattern), recursive: true });
+            }
+        }
+        expanded.push(position);
+    }
+    expanded
+}
+
+/// Match a path against a trie, recording the patterns matched at each component.
+fn explain_trail(trie: &PatternTrie, path_components: &[String]) -> TrailReport {
+    let mut steps = vec![];
+    let trail = match_trail_with(trie, path_components, |positions| {
+        steps.push(pattern_matches(positions));
+    });
+
+    TrailReport {
+        ran_out_at: steps.iter().position(|matches| matches.is_empty()),
+        steps: steps,
+        depth: trail.depth,
+        complete: trail.complete,
+        pending: trail.pending
+    }
+}
+
+fn pattern_matches(positions: &[Position]) -> Vec<PatternMatch> {
+    let mut matches = vec![];
+    let mut seen: Vec<*const PatternTrie> = vec![];
+
+    for position in positions.iter() {
+        let node = position.node as *const PatternTrie;
+        let pattern = match position.pattern {
+            Some(pattern) if !seen.contains(&node) => pattern,
+            _ => continue
+        };
+        seen.push(node);
+
+        // The node is shared by every pattern in the subtree beneath it.
+        let mut origins: Vec<Origin> = position.node.iter()
+            .flat_map(|(_, origins)| origins.iter().cloned())
+            .collect();
+        origins.sort_by(|a, b| (&a.file, a.line_number).cmp(&(&b.file, b.line_number)));
+
+        matches.push(PatternMatch {
+            pattern: format!("{:?}", pattern),
+            counted: position.is_specific(),
+            origins: origins
+        });
+    }
+    matches
+}
+
+// Tests
+
+#[cfg(test)]
+fn test_matcher(lines: &[&str]) -> Matcher {
+    use parser::parse_include_file;
+    parse_include_file(&lines.connect("\n"), Path::new("test.ska")).unwrap()
+}
+
+#[cfg(test)]
+fn is_included(matcher: &Matcher, path: &str, is_dir: bool) -> bool {
+    match matcher.classify(Path::new(path), is_dir) {
+        Included => true,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+fn is_excluded(matcher: &Matcher, path: &str, is_dir: bool) -> bool {
+    match matcher.classify(Path::new(path), is_dir) {
+        Excluded => true,
+        _ => false
+    }
+}
+
+#[test]
+fn test_recursive_exclude_anywhere() {
+    let matcher = test_matcher(&["Music", "/!/ **/.DS_Store"]);
+    assert!(is_excluded(&matcher, ".DS_Store", false));
+    assert!(is_excluded(&matcher, "Music/Album/.DS_Store", false));
+    assert!(is_included(&matcher, "Music/Album/01 Song.flac", false));
+    // Directories might contain matches for the `**` pattern, so need exploring.
+    assert!(!is_included(&matcher, "Music/Album", true));
+}
+
+#[test]
+fn test_recursive_in_middle() {
+    let matcher = test_matcher(&["code", "/!/ code/**/node_modules"]);
+    assert!(is_excluded(&matcher, "code/node_modules", true));
+    assert!(is_excluded(&matcher, "code/web/app/node_modules/x.js", false));
+    assert!(!is_excluded(&matcher, "code/web/app/index.js", false));
+    assert!(!is_excluded(&matcher, "other/node_modules", true));
+}
+
+#[test]
+fn test_trailing_recursive() {
+    let matcher = test_matcher(&["Music", "/!/ Music/Live/**"]);
+    assert!(is_excluded(&matcher, "Music/Live", true));
+    assert!(is_excluded(&matcher, "Music/Live/2001/01 Intro.flac", false));
+    assert!(!is_excluded(&matcher, "Music/Studio/01 Intro.flac", false));
+}
+
+#[test]
+fn test_regex_patterns_in_matcher() {
+    let matcher = test_matcher(&["Music", r"/!~/ Music/.*\[live\]", r"/!~~/ .*\.(tmp|part)"]);
+    assert!(is_excluded(&matcher, "Music/Album [live]", true));
+    assert!(is_excluded(&matcher, "Music/Album [live]/01 Intro.flac", false));
+    assert!(is_excluded(&matcher, "Music/Album/01 Intro.flac.part", false));
+    assert!(!is_excluded(&matcher, "Music/Album/01 Intro.flac", false));
+
+    // Only directories that a whole path regex could match beneath are left unclassified.
+    let matcher = test_matcher(&["Music", r"/!~~/ Music/Live/.*\.flac"]);
+    assert!(!is_included(&matcher, "Music", true));
+    assert!(is_included(&matcher, "Music/Studio", true));
+    assert!(is_excluded(&matcher, "Music/Live/01 Intro.flac", false));
+}
+
+#[test]
+fn test_case_insensitive_file_option() {
+    let matcher = test_matcher(&[
+        "/i/ Music/ÜÑIÇØDÉ",
+        "/=/ case-insensitive",
+        "/!~~/ .*/desktop\\.ini",
+        "/=/ case-sensitive",
+        "/!/ Music/Cover.jpg"
+    ]);
+    assert!(is_included(&matcher, "music/üñiçødé/01 Song.flac", false));
+    assert!(is_excluded(&matcher, "Music/Üñiçødé/Desktop.INI", false));
+    assert!(is_excluded(&matcher, "Music/Cover.jpg", false));
+    assert!(!is_excluded(&matcher, "Music/cover.jpg", false));
+}
+
+#[test]
+fn test_explain() {
+    let matcher = test_matcher(&["Music", "/!/ Music/Live", "/!/ **/.DS_Store"]);
+
+    let explanation = matcher.explain(Path::new("Music/Live/01 Intro.flac"), false, true);
+    assert_eq!(explanation.class, Excluded);
+    assert_eq!(explanation.include.ran_out_at, Some(1));
+    assert_eq!(explanation.include.depth, 1);
+    // The `**` pattern keeps matching, but only counts once `.DS_Store` does.
+    assert_eq!(explanation.exclude.ran_out_at, None);
+    assert_eq!(explanation.exclude.depth, 2);
+    assert!(explanation.exclude.steps[1].iter().any(|m| {
+        m.counted && m.origins.len() == 1 && m.origins[0].line_number == 2
+    }));
+
+    let explanation = matcher.explain(Path::new("Videos/film.mkv"), false, false);
+    assert_eq!(explanation.class, Both);
+    assert_eq!(explanation.is_included(), Some(false));
+    assert!(explanation.to_string().contains("excluded by default"));
+}
+
+#[test]
+fn test_classify_recursive_linked_root() {
+    use vfs::MemoryFs;
+
+    let fs = MemoryFs::new();
+    fs.add_file("music/a.flac", "a");
+    fs.add_symlink("music/link.flac", "a.flac");
+    fs.add_symlink("src", "music");
+
+    let matcher = test_matcher(&["a.flac", "link.flac"]);
+    let (include_trie, _) = matcher.classify_recursive(&fs, Path::new("src"), false, false, 2)
+        .unwrap();
+
+    // Links beneath the root are still classified as files.
+    let mut keys: Vec<Vec<String>> = include_trie.keys().map(|key| {
+        key.into_iter().cloned().collect()
+    }).collect();
+    keys.sort();
+    assert_eq!(keys, vec![vec!["a.flac".to_string()], vec!["link.flac".to_string()]]);
 }
@@ -0,0 +1,113 @@
+//! moves.rs, part of Skarn.
+//!
+//! This module detects files that have been moved or renamed in the source directory,
+//! so that they can be renamed within the destination directory rather than copied again.
+//!
+//! A file marked for copying is a candidate for a move if a file of the same size is marked for
+//! deletion. Only candidates with matching sizes are hashed, so the memory used is proportional
+//! to the number of files marked for deletion, rather than the size of the whole tree. Hashes
+//! are computed by the comparison method, so that they are cached when comparing by hash.
+
+use std::io;
+use std::path::PathBuf;
+use std::collections::HashMap;
+
+use sync::{CopyTrie, DeleteTrie};
+use sync::CopyReason::New;
+use config::Config;
+use path::key_to_path;
+use transform::transform_for;
+
+/// A file to be renamed within the destination directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    /// The path of the existing file, relative to the destination directory.
+    pub from: PathBuf,
+    /// The path to move it to, relative to the destination directory.
+    pub to: PathBuf
+}
+
+struct Candidate {
+    key: Vec<String>,
+    hash: Option<String>
+}
+
+/// Find new files with identical content to files marked for deletion.
+///
+/// Each move found is removed from both the copy and delete tries.
+pub fn detect_moves(copy_paths: &mut CopyTrie, delete_paths: &mut DeleteTrie, config: &Config)
+-> io::Result<Vec<Move>>
+{
+    let fs = &*config.fs;
+
+    // Index the regular files marked for deletion by size.
+    let mut candidates_by_size: HashMap<u64, Vec<Candidate>> = HashMap::new();
+
+    for key in delete_paths.keys() {
+        let key: Vec<String> = key.into_iter().cloned().collect();
+        let metadata = try!(fs.symlink_metadata(&config.dest_dir.join(key_to_path(&key[..]))));
+
+        // Empty files are trivially identical, and not worth moving.
+        if !metadata.is_file() || metadata.len == 0 {
+            continue;
+        }
+
+        candidates_by_size.entry(metadata.len).or_insert(vec![]).push(Candidate {
+            key: key,
+            hash: None
+        });
+    }
+
+    let new_files: Vec<Vec<String>> = copy_paths.iter()
+        .filter(|&(_, reason)| *reason == New)
+        .map(|(key, _)| key.into_iter().cloned().collect())
+        .collect();
+
+    let mut moves = vec![];
+
+    for key in new_files {
+        let relative_path = key_to_path(&key[..]);
+        let source = config.source_dir.join(&relative_path);
+        let metadata = try!(fs.symlink_metadata(&source));
+
+        // Transformed files differ from their source, so can't be found by hashing.
+        if !metadata.is_file() || transform_for(config, &relative_path).is_some() {
+            continue;
+        }
+
+        let candidates = match candidates_by_size.get_mut(&metadata.len) {
+            Some(candidates) => candidates,
+            None => continue
+        };
+
+        let source_hash = try!(config.comparison_method.hash(fs, &source));
+
+        let mut found = None;
+        for (i, candidate) in candidates.iter_mut().enumerate() {
+            if candidate.hash.is_none() {
+                let dest = config.dest_dir.join(key_to_path(&candidate.key[..]));
+                candidate.hash = Some(try!(config.comparison_method.hash(fs, &dest)));
+            }
+            if candidate.hash.as_ref() == Some(&source_hash) {
+                found = Some(i);
+                break;
+            }
+        }
+
+        // Each file in the destination can only be moved once.
+        if let Some(i) = found {
+            let candidate = candidates.swap_remove(i);
+
+            copy_paths.remove(&key[..]);
+            delete_paths.remove(&candidate.key[..]);
+
+            debug!(" Detected Move: {:?} -> {:?}", candidate.key, key);
+            moves.push(Move {
+                from: key_to_path(&candidate.key[..]),
+                to: key_to_path(&key[..])
+            });
+        }
+    }
+
+    Ok(moves)
+}
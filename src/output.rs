@@ -0,0 +1,70 @@
+//! output.rs, part of Skarn.
+//!
+//! This module formats sync plans for display, either as an itemized list or as JSON for other
+//! programs to consume.
+
+use std::io;
+
+use rustc_serialize::json::ToJson;
+
+use skarn::{Config, Plan, Report};
+use skarn::CopyReason::*;
+
+/// Print an itemized list of the changes in a sync plan, one per line.
+///
+/// Each line consists of an action code, padded to a fixed width, followed by a relative path.
+/// The action codes are:
+///
+/// * `move` for files to be renamed within the destination, shown as `<from> -> <to>`.
+/// * `new` for files with no equivalent in the destination directory.
+/// * `changed` for files whose destination copy differs from the source's.
+/// * `delete:<category>` for files to be deleted, where the category is one of the delete
+///   behaviours accepted by `--delete` (`included-no-equiv`, `excluded-equiv`,
+///   `excluded-no-equiv`).
+///
+/// Directories are shown with a trailing slash. Moves are listed first, then copies, then
+/// deletions, and each group is sorted by path.
+pub fn print_itemized(plan: &Plan, config: &Config) {
+    let mut moves = plan.moves.clone();
+    moves.sort_by(|a, b| a.to.cmp(&b.to));
+
+    for m in moves {
+        println!("{:<25} {} -> {}", "move", m.from.display(), m.to.display());
+    }
+
+    for (path, reason) in plan.copies() {
+        let code = match reason {
+            New => "new",
+            Differs => "changed"
+        };
+        println!("{:<25} {}", code, path.display());
+    }
+
+    for (path, category) in plan.deletions() {
+        let code = format!("delete:{}", category.name());
+        let is_dir = config.fs.symlink_metadata(&config.compare_dir().join(&path))
+            .map(|m| m.is_dir()).unwrap_or(false);
+        let slash = if is_dir { "/" } else { "" };
+        println!("{:<25} {}{}", code, path.display(), slash);
+    }
+}
+
+/// Print a sync plan as a single JSON object.
+///
+/// The object has `moves`, `copies` and `deletions` arrays, as described for `Report`, and a
+/// `totals` object counting the entries of each kind and their sizes in bytes.
+pub fn print_json(plan: &Plan, config: &Config) -> io::Result<()> {
+    println!("{}", try!(Report::new(plan, config)).to_json().pretty());
+    Ok(())
+}
+
+/// Print a sync plan as newline-delimited JSON, with one object per line.
+///
+/// Each object has a `type` of `move`, `copy` or `delete`, followed by a final object of type
+/// `totals`.
+pub fn print_ndjson(plan: &Plan, config: &Config) -> io::Result<()> {
+    for line in try!(Report::new(plan, config)).to_ndjson() {
+        println!("{}", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! parallel.rs, part of Skarn.
+//!
+//! This module traverses trees on several threads. On network file systems and large disk arrays,
+//! waiting for directory listings and metadata dominates the time taken to plan a sync, so
+//! several requests are kept in flight at once.
+//!
+//! Work is shared through a single queue, and the results are sorted once the traversal is done,
+//! so that they don't depend on how the threads were scheduled.
+
+use std::io;
+use std::mem;
+use std::thread;
+use std::sync::{Mutex, MutexGuard, Condvar};
+
+use crossbeam;
+
+struct Queue<T, R> {
+    pending: Vec<T>,
+    /// The number of items being processed, each of which may add more items to the queue.
+    active: usize,
+    results: Vec<R>,
+    error: Option<io::Error>
+}
+
+/// Records a panic while processing an item, so that the other threads stop waiting for it.
+struct PanicGuard<'a, T: 'a, R: 'a> {
+    queue: &'a Mutex<Queue<T, R>>,
+    ready: &'a Condvar
+}
+
+impl<'a, T, R> Drop for PanicGuard<'a, T, R> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let mut guard = lock(self.queue);
+            guard.active -= 1;
+            guard.error = Some(io::Error::new(io::ErrorKind::Other, "a traversal thread panicked"));
+            self.ready.notify_all();
+        }
+    }
+}
+
+/// Lock the queue, even if a thread panicked while holding it, as panics are recorded as errors.
+fn lock<T, R>(queue: &Mutex<Queue<T, R>>) -> MutexGuard<Queue<T, R>> {
+    queue.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Process a tree of items, starting from `roots`, on the given number of threads.
+///
+/// Processing an item yields more items to process, and some results. Once every item has been
+/// processed, the results are returned in sorted order. If processing any item fails, the
+/// traversal stops and the first error is returned. A panic while processing an item also stops
+/// the traversal, rather than leaving the other threads waiting for the item.
+///
+/// With fewer than two threads, the items are processed on the calling thread.
+pub fn traverse<T, R, F>(roots: Vec<T>, threads: usize, process: F) -> io::Result<Vec<R>>
+where T: Send, R: Send + Ord, F: Fn(T) -> io::Result<(Vec<T>, Vec<R>)> + Sync {
+    let queue = Mutex::new(Queue { pending: roots, active: 0, results: vec![], error: None });
+    let ready = Condvar::new();
+
+    {
+        let worker = || {
+            let mut guard = lock(&queue);
+            while guard.error.is_none() {
+                let next = guard.pending.pop();
+                let item = match next {
+                    Some(item) => item,
+                    // Items being processed by other threads may yet add to the queue.
+                    None if guard.active > 0 => {
+                        guard = ready.wait(guard).unwrap_or_else(|e| e.into_inner());
+                        continue;
+                    },
+                    None => break
+                };
+
+                guard.active += 1;
+                drop(guard);
+                let result = {
+                    let _panic_guard = PanicGuard { queue: &queue, ready: &ready };
+                    process(item)
+                };
+                guard = lock(&queue);
+                guard.active -= 1;
+
+                match result {
+                    Ok((items, results)) => {
+                        guard.pending.extend(items.into_iter());
+                        guard.results.extend(results.into_iter());
+                    },
+                    Err(e) => guard.error = Some(e)
+                }
+                ready.notify_all();
+            }
+        };
+
+        if threads < 2 {
+            worker();
+        } else {
+            crossbeam::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(|| worker());
+                }
+            });
+        }
+    }
+
+    let mut guard = lock(&queue);
+    if let Some(e) = guard.error.take() {
+        return Err(e);
+    }
+    let mut results = mem::replace(&mut guard.results, vec![]);
+    results.sort();
+    Ok(results)
+}
+
+// Tests
+
+#[test]
+fn test_traverse() {
+    use std::path::{Path, PathBuf};
+    use vfs::MemoryFs;
+    use path::{walk, walk_parallel};
+
+    let fs = MemoryFs::new();
+    for path in ["a/b/c", "a/b/d", "a/e", "f/g/h/i", "f/j", "k"].iter() {
+        fs.add_file(&format!("root/{}", path), "");
+    }
+
+    let mut expected = walk(&fs, Path::new("root"), false).unwrap();
+    expected.sort();
+
+    // The results are the same for any number of threads.
+    for threads in 1..5 {
+        assert_eq!(walk_parallel(&fs, Path::new("root"), false, threads).unwrap(), expected);
+    }
+
+    // Directories are listed before their contents.
+    let position = |path: &str| expected.iter().position(|p| p == Path::new(path)).unwrap();
+    assert!(position("root/f") < position("root/f/g/h/i"));
+
+    // Links to a directory above themselves are listed, but not followed.
+    fs.add_symlink("root/a/b/up", "../..");
+    let mut paths = walk(&fs, Path::new("root"), true).unwrap();
+    paths.sort();
+    assert_eq!(walk_parallel(&fs, Path::new("root"), true, 4).unwrap(), paths);
+    assert!(paths.contains(&PathBuf::from("root/a/b/up")));
+    assert!(!paths.iter().any(|p| p.starts_with("root/a/b/up/a")));
+
+    // An error stops the traversal, which would otherwise never end.
+    let result = traverse(vec![0], 4, |n: u32| {
+        if n == 10 {
+            Err(io::Error::new(io::ErrorKind::Other, "failed"))
+        } else {
+            Ok((vec![n + 1], vec![n]))
+        }
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_traverse_panic() {
+    // A panic on one thread doesn't leave the others waiting forever.
+    let result = thread::spawn(|| {
+        traverse(vec![0], 4, |n: u32| {
+            if n == 10 {
+                panic!("failed");
+            }
+            Ok((vec![n + 1], vec![n]))
+        })
+    }).join();
+    assert!(result.map(|r| r.is_err()).unwrap_or(true));
+}
@@ -1,6 +1,12 @@
 //! parser.rs, part of Skarn.
 //! This module contains functions for processing an include file into a tree of PatternNode objects.
 
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
 use regex::Regex;
 
 use sequence_trie::SequenceTrie;
@@ -8,89 +14,472 @@ use sequence_trie::SequenceTrie;
 use pattern::Pattern;
 use matcher::{Matcher, PatternTrie};
 
-use self::Prelude::{SimpleInclude, SimpleExclude, GlobInclude, GlobExclude};
-use self::ParseError::{InvalidLine, InvalidPrelude, TrivialInput};
+use self::Syntax::{Simple, Glob, ComponentRegex, PathRegex};
+use self::ParseErrorKind::{
+    InvalidLine, InvalidPrelude, InvalidPattern, TrivialInput, UnreadableFile, IncludeCycle
+};
 
 static COMMENT_LINE_REGEX: Regex = regex!("^(/#/ .*)?$");
-static LINE_REGEX: Regex = regex!(r"^(?P<prelude>/(?P<inner_prelude>[!\*]{1,2})/ )?(?P<path>[^/].+)$");
+static LINE_REGEX: Regex = regex!(r"^(?P<prelude>/(?P<inner_prelude>[^/ ]+)/ )?(?P<path>[^/].*)$");
+static PRELUDE_REGEX: Regex = regex!(r"^/[^/ ]*/");
+static INCLUDE_REGEX: Regex = regex!(r"^/@/ (?P<path>.+)$");
+static OPTION_REGEX: Regex = regex!(r"^/=/ (?P<option>.*)$");
+
+/// The syntax of the path on a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Simple paths, with only `*` and `**` wildcards (no prelude).
+    Simple,
+    /// Glob paths (`*` in the prelude).
+    Glob,
+    /// A regular expression for each path component (`~` in the prelude).
+    ComponentRegex,
+    /// A single regular expression for the whole path (`~~` in the prelude).
+    PathRegex
+}
 
-#[derive(Debug, Clone, Copy)]
-pub enum Prelude {
-    SimpleInclude,
-    SimpleExclude,
-    GlobInclude,
-    GlobExclude,
+/// The interpretation of a line, as specified by its prelude.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prelude {
+    pub syntax: Syntax,
+    /// Whether the line is an exclude pattern (`!` in the prelude).
+    pub exclude: bool,
+    /// Whether the line's patterns are case insensitive (`i` in the prelude).
+    pub case_insensitive: bool
+}
+
+impl Prelude {
+    /// Parse the characters between the slashes of a prelude, which may be given in any order.
+    fn from_str(prelude: &str) -> Option<Prelude> {
+        let (mut num_excludes, mut num_globs, mut num_regexes, mut num_is) = (0, 0, 0, 0);
+
+        for c in prelude.chars() {
+            match c {
+                '!' => num_excludes += 1,
+                'i' => num_is += 1,
+                '*' => num_globs += 1,
+                '~' => num_regexes += 1,
+                _ => return None
+            }
+        }
+
+        let syntax = match (num_globs, num_regexes) {
+            (0, 0) => Simple,
+            (1, 0) => Glob,
+            (0, 1) => ComponentRegex,
+            (0, 2) => PathRegex,
+            _ => return None
+        };
+
+        if num_excludes > 1 || num_is > 1 {
+            return None;
+        }
+
+        Some(Prelude {
+            syntax: syntax,
+            exclude: num_excludes == 1,
+            case_insensitive: num_is == 1
+        })
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum ParseError {
+/// The line of an include file that a pattern was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub file: PathBuf,
+    /// The line number, starting from 1.
+    pub line_number: usize,
+    pub line: String
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}:{}: {}", self.file.display(), self.line_number, self.line)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
     InvalidLine,
     InvalidPrelude,
-    TrivialInput
+    InvalidPattern,
+    TrivialInput,
+    UnreadableFile,
+    IncludeCycle
+}
+
+/// A syntax error in an include file, with enough context to locate and explain it.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub message: String,
+    /// The include file containing the error.
+    pub file: PathBuf,
+    /// The line number of the error, starting from 1, if it relates to a single line.
+    pub line_number: Option<usize>,
+    /// The text of the offending line.
+    pub line: String,
+    /// The column of the error within the line, starting from 1, if known.
+    pub column: Option<usize>
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, message: String) -> ParseError {
+        ParseError {
+            kind: kind,
+            message: message,
+            file: PathBuf::new(),
+            line_number: None,
+            line: String::new(),
+            column: None
+        }
+    }
+
+    pub fn with_column(mut self, column: usize) -> ParseError {
+        self.column = Some(column);
+        self
+    }
+
+    pub fn at_line(mut self, line_number: usize, line: &str) -> ParseError {
+        self.line_number = Some(line_number);
+        self.line = line.to_string();
+        self
+    }
+
+    pub fn in_file(mut self, file: &Path) -> ParseError {
+        self.file = file.to_path_buf();
+        self
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        match self.kind {
+            InvalidLine => "invalid line",
+            InvalidPrelude => "invalid prelude",
+            InvalidPattern => "invalid pattern",
+            TrivialInput => "trivial input",
+            UnreadableFile => "unreadable file",
+            IncludeCycle => "include cycle"
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        try!(write!(f, "{}", self.file.display()));
+        if let Some(line_number) = self.line_number {
+            try!(write!(f, ":{}", line_number));
+        }
+        if let Some(column) = self.column {
+            try!(write!(f, ":{}", column));
+        }
+        try!(write!(f, ": {}", self.message));
+
+        if self.line_number.is_some() {
+            try!(write!(f, "\n    {}", self.line));
+            if let Some(column) = self.column {
+                let padding: String = self.line.chars().take(column - 1).map(|c| {
+                    if c == '\t' { '\t' } else { ' ' }
+                }).collect();
+                try!(write!(f, "\n    {}^", padding));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read and parse an include file, along with any files it includes.
+pub fn parse_include_path(filename: &Path) -> Result<Matcher, ParseError> {
+    let include_file = try!(read_include_file(filename));
+    parse_include_file(&include_file, filename)
+}
+
+/// Parse the contents of an include file.
+///
+/// The filename is used for error messages, and to resolve the paths of included files.
+pub fn parse_include_file(include_file: &str, filename: &Path) -> Result<Matcher, ParseError> {
+    let mut tries = Tries {
+        include_trie: SequenceTrie::new(),
+        exclude_trie: SequenceTrie::new(),
+        num_patterns: 0
+    };
+
+    let mut include_stack = vec![canonical_path(filename)];
+    try!(parse_into(include_file, filename, &mut tries, &mut include_stack));
+
+    if tries.num_patterns == 0 {
+        return Err(
+            ParseError::new(TrivialInput, "include file contains no patterns".to_string())
+            .in_file(filename)
+        );
+    }
+
+    Ok(Matcher {
+        include_trie: tries.include_trie,
+        exclude_trie: tries.exclude_trie,
+        git_ignore: None
+    })
 }
 
-pub fn parse_include_file(include_file: &str) -> Result<Matcher, ParseError> {
-    let mut include_trie: PatternTrie = SequenceTrie::new();
-    let mut exclude_trie: PatternTrie = SequenceTrie::new();
+/// The pattern tries being built from an include file and the files it includes.
+struct Tries {
+    include_trie: PatternTrie,
+    exclude_trie: PatternTrie,
+    num_patterns: usize
+}
 
-    let mut is_trivial_tree = true;
-    for line in include_file.lines() {
+/// Parse the lines of an include file, adding patterns to the tries.
+///
+/// Options set using the `/=/` prelude apply to the rest of the file, but not to included files.
+///
+/// The include stack contains the canonical paths of the files currently being parsed,
+/// and is used to detect include cycles.
+fn parse_into(include_file: &str, filename: &Path, tries: &mut Tries, include_stack: &mut Vec<PathBuf>)
+-> Result<(), ParseError>
+{
+    let mut case_insensitive = false;
+
+    for (i, line) in include_file.lines().enumerate() {
         if COMMENT_LINE_REGEX.is_match(line) {
             continue;
         }
 
-        let (path_components, prelude) = match parse_single_line(line) {
+        // Set options for the rest of the file.
+        if let Some(captures) = OPTION_REGEX.captures(line) {
+            match captures.name("option").unwrap() {
+                "case-insensitive" => case_insensitive = true,
+                "case-sensitive" => case_insensitive = false,
+                option => return Err(
+                    ParseError::new(InvalidLine, format!(
+                        "unknown option '{}', expected 'case-insensitive' or 'case-sensitive'", option
+                    )).with_column(5).at_line(i + 1, line).in_file(filename)
+                )
+            }
+            continue;
+        }
+
+        // Splice in the patterns from included files.
+        if let Some(captures) = INCLUDE_REGEX.captures(line) {
+            let relative_path = Path::new(captures.name("path").unwrap());
+            let included_filename = match filename.parent() {
+                Some(dir) => dir.join(relative_path),
+                None => relative_path.to_path_buf()
+            };
+
+            let canonical_filename = canonical_path(&included_filename);
+            if include_stack.contains(&canonical_filename) {
+                let cycle: Vec<String> = include_stack.iter()
+                    .chain(Some(&canonical_filename).into_iter())
+                    .map(|p| p.display().to_string())
+                    .collect();
+                return Err(
+                    ParseError::new(IncludeCycle, format!("include cycle: {}", cycle.connect(" -> ")))
+                    .with_column(5).at_line(i + 1, line).in_file(filename)
+                );
+            }
+
+            let included_file = match read_include_file(&included_filename) {
+                Ok(contents) => contents,
+                Err(e) => return Err(e.with_column(5).at_line(i + 1, line).in_file(filename))
+            };
+
+            include_stack.push(canonical_filename);
+            try!(parse_into(&included_file, &included_filename, tries, include_stack));
+            include_stack.pop();
+            continue;
+        }
+
+        let (path_components, prelude) = match parse_single_line(line, case_insensitive) {
             Ok(result) => result,
-            Err(e) => return Err(e)
+            Err(e) => return Err(e.at_line(i + 1, line).in_file(filename))
         };
 
-        is_trivial_tree = false;
+        tries.num_patterns += 1;
 
-        match prelude {
-            SimpleInclude | GlobInclude => include_trie.insert(&path_components[..], ()),
-            SimpleExclude | GlobExclude => exclude_trie.insert(&path_components[..], ())
+        let trie = if prelude.exclude {
+            &mut tries.exclude_trie
+        } else {
+            &mut tries.include_trie
         };
+
+        // Identical patterns share a node, so keep track of every line they appear on.
+        let mut origins = trie.get(&path_components[..]).cloned().unwrap_or(vec![]);
+        origins.push(Origin {
+            file: filename.to_path_buf(),
+            line_number: i + 1,
+            line: line.to_string()
+        });
+        trie.insert(&path_components[..], origins);
     }
 
-    if is_trivial_tree {
-        return Err(TrivialInput);
+    Ok(())
+}
+
+fn read_include_file(filename: &Path) -> Result<String, ParseError> {
+    let mut contents = String::new();
+    match File::open(filename).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => Ok(contents),
+        Err(e) => Err(
+            ParseError::new(UnreadableFile, format!("unable to read '{}': {}", filename.display(), e))
+            .in_file(filename)
+        )
     }
+}
 
-    Ok(Matcher {
-        include_trie: include_trie,
-        exclude_trie: exclude_trie
-    })
+/// Canonicalise a path for cycle detection, falling back to the path itself if it doesn't exist.
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or(path.to_path_buf())
 }
 
-pub fn parse_single_line(line: &str) -> Result<(Vec<Pattern>, Prelude), ParseError> {
+/// Parse a single line into a list of patterns, one per component.
+///
+/// Patterns are case insensitive if `case_insensitive` is true or the prelude says so.
+pub fn parse_single_line(line: &str, case_insensitive: bool)
+-> Result<(Vec<Pattern>, Prelude), ParseError>
+{
     // Parse the line into a prelude and path.
     let captures = match LINE_REGEX.captures(line) {
         Some(captures) => captures,
-        None => return Err(InvalidLine)
+        None => return Err(invalid_line_error(line))
     };
 
     // Extract the prelude.
-    let prelude = match captures.name("prelude") {
-        None => SimpleInclude,
-        Some(_) => match captures.name("inner_prelude") {
-            Some("*") => GlobInclude,
-            Some("!") => SimpleExclude,
-            Some("!*") | Some("*!") => GlobExclude,
-            _ => return Err(InvalidPrelude)
+    let prelude = match captures.name("inner_prelude") {
+        None => Prelude { syntax: Simple, exclude: false, case_insensitive: false },
+        Some(p) => match Prelude::from_str(p) {
+            Some(prelude) => prelude,
+            None => return Err(
+                ParseError::new(InvalidPrelude, format!(
+                    "unknown prelude '/{}/', expected one of \
+                     '/!/', '/*/', '/!*/', '/~/', '/!~/', '/~~/', '/!~~/', '/@/' or '/=/', \
+                     optionally with 'i' for case insensitivity", p
+                )).with_column(1)
+            )
         }
     };
 
+    let case_insensitive = case_insensitive || prelude.case_insensitive;
+    let regex_flags = if case_insensitive { "(?i)" } else { "" };
+
     // Extract the path, which is guaranteed to be non-empty by the regex.
     let path = captures.name("path").unwrap();
+    // Columns count characters rather than bytes, like the offsets within the path.
+    let path_column = line.chars().count() - path.chars().count() + 1;
+
+    // Whole path regexes aren't split into components.
+    if prelude.syntax == PathRegex {
+        return match Pattern::path_regex_pattern(&format!("{}{}", regex_flags, path)) {
+            Ok(pattern) => Ok((vec![pattern], prelude)),
+            Err(e) => Err(
+                ParseError::new(InvalidPattern, format!("invalid regex '{}': {}", path, e))
+                .with_column(path_column)
+            )
+        };
+    }
+
+    let mut components = vec![];
+    let mut offset = 0;
 
-    let components: Vec<Pattern> = match prelude {
-        SimpleInclude | SimpleExclude => {
-            path.split('/').map(Pattern::simple_pattern).collect()
-        },
-        GlobInclude | GlobExclude => {
-            path.split('/').map(Pattern::glob_pattern).collect()
+    for component in path.split('/') {
+        // The error position is only meaningful for glob patterns, which aren't escaped.
+        let pattern = match (prelude.syntax, case_insensitive) {
+            (Simple, false) => Pattern::simple_pattern(component).map_err(|e| (0, e.msg.to_string())),
+            (Simple, true) => Pattern::case_insensitive(component, Pattern::simple_pattern)
+                .map_err(|e| (0, e.msg.to_string())),
+            (Glob, false) => Pattern::glob_pattern(component).map_err(|e| (e.pos, e.msg.to_string())),
+            (Glob, true) => Pattern::case_insensitive(component, Pattern::glob_pattern)
+                .map_err(|e| (e.pos, e.msg.to_string())),
+            (ComponentRegex, _) => Pattern::regex_pattern(&format!("{}{}", regex_flags, component))
+                .map_err(|e| (0, format!("{}", e))),
+            (PathRegex, _) => unreachable!()
+        };
+
+        match pattern {
+            Ok(pattern) => components.push(pattern),
+            Err((position, message)) => {
+                let column = path_column + offset + position;
+                return Err(
+                    ParseError::new(InvalidPattern, format!("invalid pattern '{}': {}", component, message))
+                    .with_column(column)
+                );
+            }
         }
-    };
+
+        offset += component.chars().count() + 1;
+    }
 
     Ok((components, prelude))
 }
+
+/// Explain why a line didn't match the line syntax.
+fn invalid_line_error(line: &str) -> ParseError {
+    if let Some((_, end)) = PRELUDE_REGEX.find(line) {
+        let rest = &line[end..];
+        let end_column = line[..end].chars().count();
+
+        if !rest.starts_with(" ") {
+            return ParseError::new(InvalidLine,
+                "a prelude must be followed by a single space".to_string()
+            ).with_column(end_column + 1);
+        }
+        if rest.len() == 1 {
+            return ParseError::new(InvalidLine,
+                "missing path after prelude".to_string()
+            ).with_column(end_column + 1);
+        }
+        return ParseError::new(InvalidLine,
+            "paths are relative to the source directory, and cannot begin with '/'".to_string()
+        ).with_column(end_column + 2);
+    }
+
+    ParseError::new(InvalidLine,
+        "paths are relative to the source directory, and cannot begin with '/'".to_string()
+    ).with_column(1)
+}
+
+// Tests
+
+#[test]
+fn test_parse_error_location() {
+    let include_file = "Music\n/#/ Comment\n/?/ Videos\n";
+    let error = parse_include_file(include_file, Path::new("test.ska")).err().unwrap();
+    assert_eq!(error.kind, InvalidPrelude);
+    assert_eq!(error.line_number, Some(3));
+    assert_eq!(&error.line[..], "/?/ Videos");
+
+    let error = parse_include_file("Music\n/*/ Jazz/[abc\n", Path::new("test.ska")).err().unwrap();
+    assert_eq!(error.kind, InvalidPattern);
+    assert_eq!(error.line_number, Some(2));
+    assert_eq!(error.column, Some(10));
+
+    // Columns count characters, not bytes.
+    let error = parse_include_file("/*/ Mūsïc/Jäzz/[abc\n", Path::new("test.ska")).err().unwrap();
+    assert_eq!(error.column, Some(16));
+}
+
+#[test]
+fn test_include_directive() {
+    let matcher = parse_include_path(Path::new("test/includes/main.ska")).unwrap();
+    assert_eq!(matcher.exclude_trie.children.len(), 1);
+    assert_eq!(matcher.include_trie.children.len(), 1);
+
+    let error = parse_include_path(Path::new("test/includes/cycle_a.ska")).err().unwrap();
+    assert_eq!(error.kind, IncludeCycle);
+    assert_eq!(error.file, PathBuf::from("test/includes/cycle_b.ska"));
+}
+
+#[test]
+fn test_case_insensitive_preludes() {
+    let (patterns, prelude) = parse_single_line("/!i*/ Music/*.FLAC", false).unwrap();
+    assert!(prelude.exclude && prelude.case_insensitive && prelude.syntax == Glob);
+    assert!(patterns[1].matches("song.flac"));
+
+    let (patterns, _) = parse_single_line("Videos", true).unwrap();
+    assert!(patterns[0].matches("VIDEOS"));
+    let (patterns, _) = parse_single_line("Videos", false).unwrap();
+    assert!(!patterns[0].matches("VIDEOS"));
+
+    let error = parse_include_file("/=/ shouting\n", Path::new("test.ska")).err().unwrap();
+    assert_eq!(error.kind, InvalidLine);
+}
@@ -1,4 +1,8 @@
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use vfs::FileSystem;
+use parallel::traverse;
 
 pub trait StringComponents {
     fn string_components(&self) -> Vec<String>;
@@ -9,3 +13,112 @@ impl StringComponents for Path {
         self.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect()
     }
 }
+
+/// Convert a key from a trie of path components back into a relative path.
+pub fn key_to_path<S: AsRef<str>>(key: &[S]) -> PathBuf {
+    let mut path = PathBuf::new();
+    for component in key.iter() {
+        path.push(component.as_ref());
+    }
+    path
+}
+
+/// Check whether a path is a directory, optionally following symlinks.
+pub fn is_dir(fs: &FileSystem, path: &Path, follow_links: bool) -> bool {
+    let metadata = if follow_links {
+        fs.metadata(path)
+    } else {
+        fs.symlink_metadata(path)
+    };
+    metadata.map(|m| m.is_dir()).unwrap_or(false)
+}
+
+/// Check whether a path is a symlink (without following it).
+pub fn is_symlink(fs: &FileSystem, path: &Path) -> bool {
+    fs.symlink_metadata(path).map(|m| m.is_symlink()).unwrap_or(false)
+}
+
+/// The device and inode numbers of a directory, which identify it however it is reached.
+pub type DirId = (u64, u64);
+
+/// Add a directory to the list of directories above it, before walking into it.
+///
+/// When following symlinks, a link to a directory above itself would be walked endlessly, so
+/// `None` is returned for a directory that is one of its own ancestors. Only ancestors are
+/// checked, so that other links to the same directory are still walked, and the result
+/// doesn't depend on the order of the walk.
+pub fn enter_dir(fs: &FileSystem, dir: &Path, mut ancestors: Vec<DirId>, follow_links: bool)
+-> io::Result<Option<Vec<DirId>>> {
+    // Without following links, a directory can't be reached from beneath itself.
+    if !follow_links {
+        return Ok(Some(ancestors));
+    }
+
+    let metadata = try!(fs.metadata(dir));
+    let id = (metadata.dev, metadata.ino);
+    if ancestors.contains(&id) {
+        warn!("Skipping symlink to a directory containing it: {}", dir.display());
+        return Ok(None);
+    }
+    ancestors.push(id);
+    Ok(Some(ancestors))
+}
+
+/// Recursively list every path beneath a directory, optionally following symlinks.
+///
+/// Directories are always listed before their contents. Symlinks to a directory containing
+/// them are listed, but not followed.
+pub fn walk(fs: &FileSystem, root: &Path, follow_links: bool) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    let mut stack = vec![(root.to_path_buf(), vec![])];
+
+    while let Some((dir, ancestors)) = stack.pop() {
+        let ancestors = match try!(enter_dir(fs, &dir, ancestors, follow_links)) {
+            Some(ancestors) => ancestors,
+            None => continue
+        };
+
+        for path in try!(fs.read_dir(&dir)) {
+            if is_dir(fs, &path, follow_links) {
+                stack.push((path.clone(), ancestors.clone()));
+            }
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Recursively list every path beneath a directory like `walk`, listing directories on the
+/// given number of threads.
+///
+/// The paths are sorted, so directories are still listed before their contents.
+pub fn walk_parallel(fs: &FileSystem, root: &Path, follow_links: bool, threads: usize)
+-> io::Result<Vec<PathBuf>> {
+    traverse(vec![(root.to_path_buf(), vec![])], threads, |(dir, ancestors)| {
+        let ancestors = match try!(enter_dir(fs, &dir, ancestors, follow_links)) {
+            Some(ancestors) => ancestors,
+            None => return Ok((vec![], vec![]))
+        };
+
+        let paths = try!(fs.read_dir(&dir));
+        let dirs = paths.iter()
+            .filter(|path| is_dir(fs, path, follow_links))
+            .map(|path| (path.clone(), ancestors.clone()))
+            .collect();
+        Ok((dirs, paths))
+    })
+}
+
+/// Remove a directory and everything beneath it, without following symlinks.
+pub fn remove_tree(fs: &FileSystem, root: &Path) -> io::Result<()> {
+    // Walking lists directories before their contents, so the reversed list empties each
+    // directory before removing it.
+    for path in try!(walk(fs, root, false)).iter().rev() {
+        if is_dir(fs, path, false) {
+            try!(fs.remove_dir(path));
+        } else {
+            try!(fs.remove_file(path));
+        }
+    }
+    fs.remove_dir(root)
+}
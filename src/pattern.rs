@@ -1,7 +1,11 @@
 use glob;
+use glob::PatternError;
+use regex;
 
 use std::fmt::{self, Formatter, Debug};
-use self::Pattern::{Plain, Glob};
+use std::hash::{Hash, Hasher};
+use casefold::fold_case;
+use self::Pattern::{Plain, Glob, Recursive, Regex, PathRegex, CaseInsensitive};
 
 /// Enum for different pattern types.
 #[derive(PartialEq, Eq, Hash, Clone)]
@@ -9,39 +13,173 @@ pub enum Pattern {
     /// Just a string, no wildcards.
     Plain(String),
     /// Glob pattern, using any globbing constructs.
-    Glob(glob::Pattern)
+    Glob(glob::Pattern),
+    /// The `**` wildcard, which matches zero or more path components.
+    Recursive,
+    /// Regular expression, matched against a single path component.
+    Regex(RegexPattern),
+    /// Regular expression, matched against a whole relative path.
+    PathRegex(RegexPattern),
+    /// Case insensitive pattern, created from case folded text and matched against case folded
+    /// strings.
+    CaseInsensitive(Box<Pattern>)
+}
+
+/// A regular expression which must match an entire string.
+///
+/// Regular expressions are compared and hashed by their source text.
+#[derive(Clone)]
+pub struct RegexPattern {
+    source: String,
+    regex: regex::Regex
+}
+
+impl RegexPattern {
+    pub fn new(source: &str) -> Result<RegexPattern, regex::Error> {
+        let regex = try!(regex::Regex::new(&format!("^(?:{})$", source)));
+        Ok(RegexPattern {
+            source: source.to_string(),
+            regex: regex
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source[..]
+    }
+
+    pub fn is_match(&self, string: &str) -> bool {
+        self.regex.is_match(string)
+    }
+
+    /// The literal text at the start of every match, which is empty if it can't be determined.
+    fn literal_prefix(&self) -> String {
+        // Alternatives may start differently.
+        if self.source.contains('|') {
+            return String::new();
+        }
+
+        let mut prefix = String::new();
+        let mut chars = self.source.chars().peekable();
+        while let Some(c) = chars.next() {
+            if "\\.+*?()[]{}^$".contains(c) {
+                break;
+            }
+            // A quantifier may leave the character out.
+            match chars.peek() {
+                Some(&next) if "?*{".contains(next) => break,
+                _ => prefix.push(c)
+            }
+        }
+        prefix
+    }
+}
+
+impl PartialEq for RegexPattern {
+    fn eq(&self, other: &RegexPattern) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for RegexPattern {}
+
+impl Hash for RegexPattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.source.hash(state);
+    }
 }
 
 impl Debug for Pattern {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
         match *self {
             Plain(ref s) => s.fmt(fmt),
-            Glob(_) => "Glob".fmt(fmt)
+            Glob(ref p) => write!(fmt, "/*/ {}", p),
+            Recursive => "**".fmt(fmt),
+            Regex(ref r) => write!(fmt, "/~/ {}", r.as_str()),
+            PathRegex(ref r) => write!(fmt, "/~~/ {}", r.as_str()),
+            CaseInsensitive(ref p) => write!(fmt, "{:?} (case insensitive)", p)
         }
     }
 }
 
 impl Pattern {
     /// Create a Pattern for part of a simple path (only '*' wildcards).
-    pub fn simple_pattern(pattern: &str) -> Pattern {
+    pub fn simple_pattern(pattern: &str) -> Result<Pattern, PatternError> {
+        if pattern == "**" {
+            return Ok(Recursive);
+        }
+
         let contains_wildcards = Pattern::scan_for_wildcards(pattern);
 
         // Remove backslash escapes and create a plain pattern if no wildcards were found
         if !contains_wildcards {
-            Pattern::create_plain_pattern(pattern)
+            Ok(Pattern::create_plain_pattern(pattern))
         }
         // Otherwise, create a glob pattern with non-star wildcards escaped
         else {
             let escaped_pattern = Pattern::escape_all_but_star(pattern);
-            // FIXME: Remove unwrap.
-            Glob(glob::Pattern::new(&escaped_pattern[..]).unwrap())
+            Ok(Glob(try!(glob::Pattern::new(&escaped_pattern[..]))))
         }
     }
 
     /// Create a Glob Pattern from a string.
-    pub fn glob_pattern(pattern: &str) -> Pattern {
-        // FIXME!
-        Glob(glob::Pattern::new(pattern).unwrap())
+    pub fn glob_pattern(pattern: &str) -> Result<Pattern, PatternError> {
+        if pattern == "**" {
+            return Ok(Recursive);
+        }
+        Ok(Glob(try!(glob::Pattern::new(pattern))))
+    }
+
+    /// Create a Regex Pattern, to match a single path component.
+    pub fn regex_pattern(pattern: &str) -> Result<Pattern, regex::Error> {
+        Ok(Regex(try!(RegexPattern::new(pattern))))
+    }
+
+    /// Create a Regex Pattern, to match a whole path.
+    pub fn path_regex_pattern(pattern: &str) -> Result<Pattern, regex::Error> {
+        Ok(PathRegex(try!(RegexPattern::new(pattern))))
+    }
+
+    /// Create a case insensitive pattern, by applying a constructor to case folded text.
+    ///
+    /// Regex patterns should use the `(?i)` flag instead, as case folding alters escapes.
+    pub fn case_insensitive<E, F>(pattern: &str, constructor: F) -> Result<Pattern, E>
+    where F: Fn(&str) -> Result<Pattern, E> {
+        match try!(constructor(&fold_case(pattern))) {
+            Recursive => Ok(Recursive),
+            inner => Ok(CaseInsensitive(box inner))
+        }
+    }
+
+    /// Check if this pattern matches whole paths, rather than single components.
+    pub fn is_path_pattern(&self) -> bool {
+        match *self {
+            PathRegex(_) => true,
+            _ => false
+        }
+    }
+
+    /// Check if this whole path pattern could match a path beneath a directory, given the
+    /// directory's path (with components separated by `/`).
+    ///
+    /// Only the literal text at the start of the regex is considered, so a regex starting with
+    /// a wildcard could match beneath every directory.
+    pub fn could_match_beneath(&self, dir: &str) -> bool {
+        match *self {
+            PathRegex(ref regex) => {
+                let prefix = regex.literal_prefix();
+                let dir = format!("{}/", dir);
+                dir == "/" || dir.starts_with(&prefix) || prefix.starts_with(&dir)
+            },
+            _ => false
+        }
+    }
+
+    /// Check if this is the `**` wildcard, which may match any number of components.
+    pub fn is_recursive(&self) -> bool {
+        match *self {
+            Recursive => true,
+            _ => false
+        }
     }
 
     /// Scan a simple pattern for unescaped '*' characters.
@@ -142,6 +280,10 @@ impl Pattern {
     }
 
     /// Check if a string matches the pattern.
+    ///
+    /// The `**` wildcard matches any single component. Matching it against multiple components
+    /// is handled by the `Matcher`, as is matching whole path patterns against each of a path's
+    /// prefixes (with components separated by `/`).
     pub fn matches(&self, string: &str) -> bool {
         match *self {
             Plain(ref pattern) => {
@@ -150,6 +292,16 @@ impl Pattern {
 
             Glob(ref pattern) => {
                 pattern.matches(string)
+            },
+
+            Recursive => true,
+
+            Regex(ref regex) | PathRegex(ref regex) => {
+                regex.is_match(string)
+            },
+
+            CaseInsensitive(ref pattern) => {
+                pattern.matches(&fold_case(string))
             }
         }
     }
@@ -159,20 +311,20 @@ impl Pattern {
 
 #[test]
 fn test_plain_patterns() {
-    assert!(Pattern::simple_pattern("Hello World!").matches("Hello World!"));
-    assert!(!Pattern::simple_pattern("Hello World!").matches("Hello World"));
+    assert!(Pattern::simple_pattern("Hello World!").unwrap().matches("Hello World!"));
+    assert!(!Pattern::simple_pattern("Hello World!").unwrap().matches("Hello World"));
 }
 
 #[test]
 fn test_simple_pattern_escaping() {
-    assert!(Pattern::simple_pattern(r"Backslash \\Wow").matches(r"Backslash \Wow"));
-    assert!(Pattern::simple_pattern(r"Star \* Escape").matches("Star * Escape"));
-    assert!(!Pattern::simple_pattern(r"Star \* Escape").matches("Star X Escape"));
+    assert!(Pattern::simple_pattern(r"Backslash \\Wow").unwrap().matches(r"Backslash \Wow"));
+    assert!(Pattern::simple_pattern(r"Star \* Escape").unwrap().matches("Star * Escape"));
+    assert!(!Pattern::simple_pattern(r"Star \* Escape").unwrap().matches("Star X Escape"));
 }
 
 #[test]
 fn test_simple_pattern_matching() {
-    let js = Pattern::simple_pattern("J*S");
+    let js = Pattern::simple_pattern("J*S").unwrap();
     let matches = vec![
         "JS",
         "J.S",
@@ -195,12 +347,65 @@ fn test_simple_pattern_matching() {
 
 #[test]
 fn test_simple_pattern_wildcards() {
-    assert!(Pattern::simple_pattern("App*e [cow]?").matches("Apple [cow]?"));
-    assert!(!Pattern::simple_pattern("App*e [cow]?").matches("Apple cd"));
+    assert!(Pattern::simple_pattern("App*e [cow]?").unwrap().matches("Apple [cow]?"));
+    assert!(!Pattern::simple_pattern("App*e [cow]?").unwrap().matches("Apple cd"));
 }
 
 #[test]
 fn test_glob_pattern_wildcards() {
-    assert!(Pattern::glob_pattern("App*e [cow]?").matches("Apple cd"));
-    assert!(!Pattern::glob_pattern("Apple [cow]?").matches("Apple [cow]?"));
+    assert!(Pattern::glob_pattern("App*e [cow]?").unwrap().matches("Apple cd"));
+    assert!(!Pattern::glob_pattern("Apple [cow]?").unwrap().matches("Apple [cow]?"));
+}
+
+#[test]
+fn test_recursive_wildcard() {
+    assert!(Pattern::simple_pattern("**").unwrap().is_recursive());
+    assert!(Pattern::glob_pattern("**").unwrap().is_recursive());
+    assert!(!Pattern::simple_pattern(r"\*\*").unwrap().is_recursive());
+    assert!(!Pattern::simple_pattern("**.flac").unwrap().is_recursive());
+}
+
+#[test]
+fn test_regex_patterns() {
+    let disc = Pattern::regex_pattern(r"(CD|Disc) ?[0-9]+").unwrap();
+    assert!(disc.matches("CD1"));
+    assert!(disc.matches("Disc 2"));
+    assert!(!disc.matches("Bonus CD1"));
+
+    let live = Pattern::path_regex_pattern(r".*\[live\].*").unwrap();
+    assert!(live.is_path_pattern());
+    assert!(live.matches("Artist/Album [live]/01 Intro.flac"));
+    assert!(!live.matches("Artist/Album/01 Intro.flac"));
+}
+
+#[test]
+fn test_case_insensitive_patterns() {
+    let artist = Pattern::case_insensitive("ÜÑIÇØDÉ", Pattern::simple_pattern).unwrap();
+    assert!(artist.matches("üñiçødé"));
+    assert!(artist.matches("Üñiçødé"));
+    assert!(!artist.matches("unicode"));
+
+    let flac = Pattern::case_insensitive("*.FLAC", Pattern::glob_pattern).unwrap();
+    assert!(flac.matches("01 Song.flac"));
+    assert!(flac.matches("01 Song.Flac"));
+
+    let street = Pattern::case_insensitive("STRASSE", Pattern::simple_pattern).unwrap();
+    assert!(street.matches("Straße"));
+
+    assert!(Pattern::case_insensitive("**", Pattern::simple_pattern).unwrap().is_recursive());
+}
+
+#[test]
+fn test_path_regex_beneath() {
+    let live = Pattern::path_regex_pattern(r"Music/Live/.*\.flac").unwrap();
+    assert!(live.could_match_beneath(""));
+    assert!(live.could_match_beneath("Music"));
+    assert!(live.could_match_beneath("Music/Live/2001"));
+    assert!(!live.could_match_beneath("Videos"));
+    assert!(!live.could_match_beneath("Music/Studio"));
+
+    // Optional characters and alternatives end the literal prefix.
+    assert!(Pattern::path_regex_pattern("Musics?/x").unwrap().could_match_beneath("Music"));
+    assert!(Pattern::path_regex_pattern("Music/a|Videos/b").unwrap().could_match_beneath("Videos"));
+    assert!(Pattern::path_regex_pattern(r".*\.part").unwrap().could_match_beneath("Videos"));
 }
@@ -0,0 +1,69 @@
+//! plan.rs, part of Skarn.
+//!
+//! This module computes sync plans: the moves, copies and deletions needed to bring a
+//! destination directory up to date with a source directory.
+
+use std::io;
+use std::path::PathBuf;
+
+use sync::{sync, CopyTrie, DeleteTrie, CopyReason};
+use matcher::Matcher;
+use moves::{Move, detect_moves};
+use config::{Config, DeleteBehaviour};
+use path::key_to_path;
+
+/// The set of changes required to sync a destination directory with its source.
+pub struct Plan {
+    /// Files to be renamed within the destination directory.
+    pub moves: Vec<Move>,
+    /// Paths relative to the source directory to be copied, with the reason for each.
+    pub copy_paths: CopyTrie,
+    /// Paths relative to the destination directory to be deleted, with the category of each.
+    pub delete_paths: DeleteTrie
+}
+
+impl Plan {
+    /// Check whether the destination directory is already up to date.
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty() && self.copy_paths.keys().next().is_none() &&
+            self.delete_paths.keys().next().is_none()
+    }
+
+    /// The paths to be copied, sorted, along with the reason each needs copying.
+    pub fn copies(&self) -> Vec<(PathBuf, CopyReason)> {
+        let mut copies: Vec<(PathBuf, CopyReason)> = self.copy_paths.iter()
+            .map(|(key, reason)| (key_to_path(&key[..]), *reason))
+            .collect();
+        copies.sort_by(|a, b| a.0.cmp(&b.0));
+        copies
+    }
+
+    /// The paths to be deleted, sorted, along with the category of each.
+    pub fn deletions(&self) -> Vec<(PathBuf, DeleteBehaviour)> {
+        let mut deletions: Vec<(PathBuf, DeleteBehaviour)> = self.delete_paths.iter()
+            .map(|(key, category)| (key_to_path(&key[..]), *category))
+            .collect();
+        deletions.sort_by(|a, b| a.0.cmp(&b.0));
+        deletions
+    }
+}
+
+/// Compute the plan for syncing the source directory to the destination directory.
+///
+/// Moves are only detected if `config.detect_moves` is set. Once planning is complete, the
+/// comparison method's `finish` method should be called to save any state it keeps.
+pub fn plan(matcher: &Matcher, config: &Config) -> io::Result<Plan> {
+    let (mut copy_paths, mut delete_paths) = try!(sync(matcher, config));
+
+    let moves = if config.detect_moves {
+        try!(detect_moves(&mut copy_paths, &mut delete_paths, config))
+    } else {
+        vec![]
+    };
+
+    Ok(Plan {
+        moves: moves,
+        copy_paths: copy_paths,
+        delete_paths: delete_paths
+    })
+}
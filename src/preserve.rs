@@ -0,0 +1,78 @@
+//! preserve.rs, part of Skarn.
+//!
+//! This module copies permissions, ownership and timestamps from source files to their copies.
+//!
+//! Preserved modification times make the copies indistinguishable from their source files to
+//! the metadata comparison method, so a repeated sync has nothing to do.
+
+use std::io;
+use std::path::Path;
+use std::collections::HashSet;
+
+use libc;
+use filetime::FileTime;
+
+use vfs::FileSystem;
+use config::Preserve;
+use config::Preserve::*;
+
+/// Copy the selected metadata from `source` to `dest`.
+///
+/// If `dest` is a symlink, only its ownership is set, as links have no permissions of their own.
+/// Ownership is only set when running as root.
+pub fn preserve_metadata(fs: &FileSystem, source: &Path, dest: &Path, preserve: &HashSet<Preserve>)
+-> io::Result<()> {
+    if preserve.is_empty() {
+        return Ok(());
+    }
+
+    let dest_is_link = try!(fs.symlink_metadata(dest)).is_symlink();
+
+    let source_metadata = if dest_is_link {
+        try!(fs.symlink_metadata(source))
+    } else {
+        try!(fs.metadata(source))
+    };
+
+    if preserve.contains(&Ownership) && unsafe { libc::geteuid() } == 0 {
+        try!(fs.set_owner(dest, source_metadata.uid, source_metadata.gid));
+    }
+
+    if dest_is_link {
+        return Ok(());
+    }
+
+    if preserve.contains(&Mode) {
+        try!(fs.set_mode(dest, source_metadata.mode));
+    }
+
+    if preserve.contains(&Times) {
+        let atime = FileTime::from_seconds_since_1970(source_metadata.atime as u64,
+                                                      source_metadata.atime_nsec as u32);
+        let mtime = FileTime::from_seconds_since_1970(source_metadata.mtime as u64,
+                                                      source_metadata.mtime_nsec as u32);
+        try!(fs.set_times(dest, atime, mtime));
+    }
+
+    Ok(())
+}
+
+// Tests
+
+#[test]
+fn test_preserve_metadata() {
+    use vfs::MemoryFs;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/a.flac", "a");
+    fs.add_file("dst/a.flac", "a");
+    fs.set_mode(Path::new("src/a.flac"), 0o600).unwrap();
+    fs.set_mtime(Path::new("src/a.flac"), 1000).unwrap();
+
+    let preserve: HashSet<Preserve> = [Mode, Times].iter().cloned().collect();
+    preserve_metadata(&fs, Path::new("src/a.flac"), Path::new("dst/a.flac"), &preserve).unwrap();
+
+    let metadata = fs.metadata(Path::new("dst/a.flac")).unwrap();
+    assert_eq!(metadata.mode, 0o600);
+    assert_eq!(metadata.mtime, 1000);
+}
@@ -0,0 +1,246 @@
+//! report.rs, part of Skarn.
+//!
+//! This module summarises sync plans for other programs to consume, listing the size of each
+//! file involved and serialising the result as JSON.
+
+use std::io;
+use std::path::Path;
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use plan::Plan;
+use sync::CopyReason;
+use sync::CopyReason::*;
+use config::{Config, DeleteBehaviour};
+use config::SymlinkMode::Follow;
+use path::is_dir;
+use vfs::FileSystem;
+
+/// A sync plan along with the size of each file involved, for machine-readable output.
+///
+/// Moves are sorted by destination path, and copies and deletions by path. Sizes are those of
+/// the file being moved, copied or deleted, without following links in the destination.
+/// Directories have a size of zero.
+///
+/// Plans hold file names with any invalid UTF-8 replaced, which could name a different file in
+/// the output, so a plan involving such a file can't be reported.
+pub struct Report {
+    pub moves: Vec<MoveEntry>,
+    pub copies: Vec<CopyEntry>,
+    pub deletions: Vec<DeleteEntry>
+}
+
+pub struct MoveEntry {
+    pub from: String,
+    pub to: String,
+    pub size: u64
+}
+
+pub struct CopyEntry {
+    pub path: String,
+    pub reason: CopyReason,
+    pub size: u64
+}
+
+pub struct DeleteEntry {
+    pub path: String,
+    pub category: DeleteBehaviour,
+    pub is_dir: bool,
+    pub size: u64
+}
+
+impl Report {
+    pub fn new(plan: &Plan, config: &Config) -> io::Result<Report> {
+        let fs = &*config.fs;
+        let dest_dir = config.compare_dir();
+
+        let mut moves = plan.moves.clone();
+        moves.sort_by(|a, b| a.to.cmp(&b.to));
+
+        let mut report = Report { moves: vec![], copies: vec![], deletions: vec![] };
+
+        for m in moves {
+            report.moves.push(MoveEntry {
+                size: try!(size(fs, &dest_dir.join(&m.from), false)),
+                from: path_string(&m.from),
+                to: path_string(&m.to)
+            });
+        }
+        for (path, reason) in plan.copies() {
+            report.copies.push(CopyEntry {
+                size: try!(size(fs, &config.source_dir.join(&path), config.symlink_mode == Follow)),
+                path: path_string(&path),
+                reason: reason
+            });
+        }
+        for (path, category) in plan.deletions() {
+            report.deletions.push(DeleteEntry {
+                size: try!(size(fs, &dest_dir.join(&path), false)),
+                is_dir: is_dir(fs, &dest_dir.join(&path), false),
+                path: path_string(&path),
+                category: category
+            });
+        }
+
+        Ok(report)
+    }
+
+    pub fn totals(&self) -> Json {
+        let mut totals = BTreeMap::new();
+        totals.insert("moves".to_string(), self.moves.len().to_json());
+        totals.insert("copies".to_string(), self.copies.len().to_json());
+        totals.insert("copy_bytes".to_string(), self.copies.iter().fold(0, |n, c| n + c.size).to_json());
+        totals.insert("deletions".to_string(), self.deletions.len().to_json());
+        totals.insert("delete_bytes".to_string(), self.deletions.iter().fold(0, |n, d| n + d.size).to_json());
+        Json::Object(totals)
+    }
+
+    pub fn to_ndjson(&self) -> Vec<Json> {
+        let mut lines: Vec<Json> = vec![];
+        lines.extend(self.moves.iter().map(|m| with_type("move", m.to_json())));
+        lines.extend(self.copies.iter().map(|c| with_type("copy", c.to_json())));
+        lines.extend(self.deletions.iter().map(|d| with_type("delete", d.to_json())));
+        lines.push(with_type("totals", self.totals()));
+        lines
+    }
+}
+
+impl ToJson for Report {
+    fn to_json(&self) -> Json {
+        let mut report = BTreeMap::new();
+        report.insert("moves".to_string(), self.moves.to_json());
+        report.insert("copies".to_string(), self.copies.to_json());
+        report.insert("deletions".to_string(), self.deletions.to_json());
+        report.insert("totals".to_string(), self.totals());
+        Json::Object(report)
+    }
+}
+
+impl ToJson for MoveEntry {
+    fn to_json(&self) -> Json {
+        let mut entry = BTreeMap::new();
+        entry.insert("from".to_string(), self.from.to_json());
+        entry.insert("to".to_string(), self.to.to_json());
+        entry.insert("size".to_string(), self.size.to_json());
+        Json::Object(entry)
+    }
+}
+
+impl ToJson for CopyEntry {
+    fn to_json(&self) -> Json {
+        let reason = match self.reason {
+            New => "new",
+            Differs => "differs"
+        };
+        let mut entry = BTreeMap::new();
+        entry.insert("path".to_string(), self.path.to_json());
+        entry.insert("reason".to_string(), reason.to_json());
+        entry.insert("size".to_string(), self.size.to_json());
+        Json::Object(entry)
+    }
+}
+
+impl ToJson for DeleteEntry {
+    fn to_json(&self) -> Json {
+        let mut entry = BTreeMap::new();
+        entry.insert("path".to_string(), self.path.to_json());
+        entry.insert("category".to_string(), self.category.name().to_json());
+        entry.insert("is_dir".to_string(), self.is_dir.to_json());
+        entry.insert("size".to_string(), self.size.to_json());
+        Json::Object(entry)
+    }
+}
+
+/// Get the size of a file in the plan, or zero for a directory.
+fn size(fs: &FileSystem, path: &Path, follow_links: bool) -> io::Result<u64> {
+    let metadata = if follow_links { fs.metadata(path) } else { fs.symlink_metadata(path) };
+
+    match metadata {
+        Ok(metadata) => Ok(if metadata.is_dir() { 0 } else { metadata.len }),
+        // A name with invalid UTF-8 replaced doesn't exist, so it's found missing here.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound &&
+                      path.to_string_lossy().contains('\u{FFFD}') => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the name of {} isn't valid UTF-8, so it can't be reported", path.display())
+        )),
+        Err(e) => Err(e)
+    }
+}
+
+/// Convert a path from the plan, whose components are all valid UTF-8, to a string.
+fn path_string(path: &Path) -> String {
+    path.to_str().unwrap().to_string()
+}
+
+/// Add a `type` field to a JSON object.
+fn with_type(kind: &str, object: Json) -> Json {
+    match object {
+        Json::Object(mut fields) => {
+            fields.insert("type".to_string(), kind.to_json());
+            Json::Object(fields)
+        },
+        other => other
+    }
+}
+
+// Tests
+
+#[test]
+fn test_json_report() {
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+    use plan::plan;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "aaaa");
+    fs.add_file("src/Music/b.flac", "bb");
+    fs.add_file("dst/Music/b.flac", "b");
+    fs.add_file("dst/Music/c.flac", "ccc");
+
+    let config = test_config(fs);
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+    let report = Report::new(&plan(&matcher, &config).unwrap(), &config).unwrap();
+
+    let json = report.to_json();
+    let copies = json.find("copies").unwrap().as_array().unwrap();
+    assert_eq!(copies.len(), 2);
+    assert_eq!(copies[0].find("path").unwrap().as_string(), Some("Music/a.flac"));
+    assert_eq!(copies[0].find("reason").unwrap().as_string(), Some("new"));
+    assert_eq!(copies[1].find("reason").unwrap().as_string(), Some("differs"));
+
+    let deletions = json.find("deletions").unwrap().as_array().unwrap();
+    assert_eq!(deletions[0].find("category").unwrap().as_string(), Some("included-no-equiv"));
+
+    let totals = json.find("totals").unwrap();
+    assert_eq!(totals.find("copy_bytes").unwrap().as_u64(), Some(6));
+    assert_eq!(totals.find("delete_bytes").unwrap().as_u64(), Some(3));
+
+    let lines = report.to_ndjson();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[3].find("type").unwrap().as_string(), Some("totals"));
+    for line in lines.iter() {
+        assert!(Json::from_str(&line.to_string()).is_ok());
+    }
+}
+
+#[test]
+fn test_non_utf8_paths() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+    use plan::plan;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.create_dir_all(Path::new("dst/Music")).unwrap();
+    fs.write(Path::new(OsStr::from_bytes(b"dst/Music/\xff.flac")), b"b").unwrap();
+
+    let config = test_config(fs);
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+    let result = Report::new(&plan(&matcher, &config).unwrap(), &config);
+    assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
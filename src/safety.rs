@@ -0,0 +1,267 @@
+//! safety.rs, part of Skarn.
+//!
+//! This module guards against syncs that would delete large parts of the destination directory
+//! (or of either directory, for bidirectional syncs), like a sync from an empty directory or from
+//! a mount point whose file system isn't mounted.
+//!
+//! All of the checks can be overridden with `--force`.
+
+use std::io;
+use std::path::Path;
+
+use plan::Plan;
+use bisync::BisyncPlan;
+use matcher::Matcher;
+use matcher::Class::*;
+use config::Config;
+use config::SymlinkMode::Follow;
+use path::{walk_parallel, is_dir, enter_dir};
+use error::Error;
+
+/// Check that a plan is safe to execute, returning an error describing the problem if not.
+pub fn check_plan(plan: &Plan, matcher: &Matcher, config: &Config) -> Result<(), Error> {
+    if config.force {
+        return Ok(());
+    }
+
+    try!(check_mount(config, &config.source_dir, "source"));
+
+    let num_deletions = plan.delete_paths.keys().count();
+
+    if num_deletions == 0 {
+        return Ok(());
+    }
+
+    try!(check_not_empty(matcher, config, &config.source_dir, num_deletions));
+    try!(check_max_delete(num_deletions, config));
+    check_max_delete_percent(num_deletions, config, config.compare_dir(), "destination")
+}
+
+/// Check that a bidirectional sync is safe, in the same way as a one-way sync.
+///
+/// Both directories are expected to be mount points, and either having no included files is a
+/// problem, as files would be deleted from the other.
+/// The count limit applies to the total number of deletions from both directories, and the
+/// percentage limit to each directory separately.
+pub fn check_bisync(plan: &BisyncPlan, matcher: &Matcher, config: &Config) -> Result<(), Error> {
+    if config.force {
+        return Ok(());
+    }
+
+    try!(check_mount(config, &config.source_dir, "source"));
+    try!(check_mount(config, &config.dest_dir, "destination"));
+
+    let (source_deletions, dest_deletions) = plan.num_deletions();
+
+    if dest_deletions > 0 {
+        try!(check_not_empty(matcher, config, &config.source_dir, dest_deletions));
+    }
+    if source_deletions > 0 {
+        try!(check_not_empty(matcher, config, &config.dest_dir, source_deletions));
+    }
+
+    try!(check_max_delete(source_deletions + dest_deletions, config));
+    try!(check_max_delete_percent(source_deletions, config, &config.source_dir, "source"));
+    check_max_delete_percent(dest_deletions, config, &config.dest_dir, "destination")
+}
+
+/// Check that a directory is a mount point, if one is expected. The name is the directory's role,
+/// like "source".
+fn check_mount(config: &Config, dir: &Path, name: &str) -> Result<(), Error> {
+    let fs = &*config.fs;
+
+    // An unmounted mount point is an ordinary directory on its parent's device.
+    if config.expect_mount {
+        let device = try!(fs.metadata(dir)).dev;
+        let parent_device = try!(fs.metadata(&dir.join(".."))).dev;
+
+        if device == parent_device {
+            return Err(
+                Error::new("directory is not a mount point")
+                .with_detail(format!(
+                    "the {} directory {} is not a mount point, is its file system mounted?",
+                    name, dir.display()
+                ))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check that a directory contains at least one included file, as an empty directory (or one
+/// holding only empty directories or excluded files) suggests that something is wrong.
+fn check_not_empty(matcher: &Matcher, config: &Config, dir: &Path, num_deletions: usize)
+-> Result<(), Error> {
+    if !try!(has_included_file(matcher, config, dir)) {
+        return Err(
+            Error::new("directory is empty")
+            .with_detail(format!(
+                "the directory {} contains no included files, but {} paths would be deleted",
+                dir.display(), num_deletions
+            ))
+        );
+    }
+    Ok(())
+}
+
+/// Search a directory for an included file, stopping at the first one found.
+fn has_included_file(matcher: &Matcher, config: &Config, root: &Path) -> io::Result<bool> {
+    let fs = &*config.fs;
+    let follow_links = config.symlink_mode == Follow;
+    let mut stack = vec![(root.to_path_buf(), vec![])];
+
+    while let Some((dir, ancestors)) = stack.pop() {
+        let ancestors = match try!(enter_dir(fs, &dir, ancestors, follow_links)) {
+            Some(ancestors) => ancestors,
+            None => continue
+        };
+
+        for path in try!(fs.read_dir(&dir)) {
+            if is_dir(fs, &path, follow_links) {
+                stack.push((path, ancestors.clone()));
+                continue;
+            }
+            let included = match matcher.classify(path.relative_from(root).unwrap(), false) {
+                Included => true,
+                Excluded => false,
+                Both => config.include_by_default
+            };
+            if included {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn check_max_delete(num_deletions: usize, config: &Config) -> Result<(), Error> {
+    if let Some(max_delete) = config.max_delete {
+        if num_deletions > max_delete {
+            return Err(
+                Error::new("too many deletions")
+                .with_detail(format!(
+                    "{} paths would be deleted, but --max-delete is {}", num_deletions, max_delete
+                ))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check the deletions from a directory against the percentage limit, relative to the number of
+/// paths in the directory. The name is the directory's role, like "destination".
+fn check_max_delete_percent(num_deletions: usize, config: &Config, dir: &Path, name: &str)
+-> Result<(), Error> {
+    let max_percent = match config.max_delete_percent {
+        Some(max_percent) if num_deletions > 0 => max_percent,
+        _ => return Ok(())
+    };
+
+    let paths = try!(walk_parallel(&*config.fs, dir, false, config.threads));
+    let num_paths = paths.len();
+    let percent = 100.0 * num_deletions as f64 / num_paths as f64;
+
+    if percent > max_percent {
+        return Err(
+            Error::new("too many deletions")
+            .with_detail(format!(
+                "{:.1}% of the {} ({} of {} paths) would be deleted, \
+                 but --max-delete-percent is {}",
+                percent, name, num_deletions, num_paths, max_percent
+            ))
+        );
+    }
+    Ok(())
+}
+
+// Tests
+
+#[cfg(test)]
+fn test_matcher() -> Matcher {
+    use parser::parse_include_file;
+    parse_include_file("Music\n/!/ Videos", Path::new("test.ska")).unwrap()
+}
+
+#[cfg(test)]
+fn check(config: &Config) -> Result<(), Error> {
+    use plan::plan;
+
+    let matcher = test_matcher();
+    check_plan(&plan(&matcher, config).unwrap(), &matcher, config)
+}
+
+#[test]
+fn test_deletion_limits() {
+    use vfs::MemoryFs;
+    use config::test_config;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    for name in ["b", "c", "d"].iter() {
+        fs.add_file(&format!("dst/Music/{}.flac", name), name);
+    }
+
+    // The 3 files are 75% of the 4 paths in the destination (including the directory).
+    let mut config = test_config(fs);
+    assert!(check(&config).is_ok());
+
+    config.max_delete = Some(2);
+    assert!(check(&config).is_err());
+    config.max_delete = Some(3);
+    assert!(check(&config).is_ok());
+
+    config.max_delete_percent = Some(50.0);
+    assert!(check(&config).is_err());
+    config.force = true;
+    assert!(check(&config).is_ok());
+}
+
+#[test]
+fn test_empty_source() {
+    use vfs::{MemoryFs, FileSystem};
+    use config::test_config;
+
+    let fs = MemoryFs::new();
+    fs.create_dir_all(Path::new("src")).unwrap();
+    fs.add_file("dst/Music/a.flac", "a");
+
+    let config = test_config(fs);
+    assert!(check(&config).is_err());
+
+    // Empty directories and excluded files don't count.
+    config.fs.create_dir_all(Path::new("src/Music/Album")).unwrap();
+    config.fs.create_dir_all(Path::new("src/Videos")).unwrap();
+    config.fs.write(Path::new("src/Videos/film.mkv"), b"film").unwrap();
+    assert!(check(&config).is_err());
+
+    config.fs.write(Path::new("src/Music/Album/a.flac"), b"a").unwrap();
+    assert!(check(&config).is_ok());
+}
+
+#[test]
+fn test_bisync_deletion_percent() {
+    use vfs::{MemoryFs, FileSystem};
+    use config::test_config;
+    use bisync::{State, plan_bisync, execute_bisync};
+
+    let fs = MemoryFs::new();
+    for name in ["a", "b", "c", "d"].iter() {
+        fs.add_file(&format!("src/Music/{}.flac", name), name);
+        fs.add_file(&format!("dst/Music/{}.flac", name), name);
+    }
+
+    let mut config = test_config(fs);
+    let matcher = test_matcher();
+    let mut state = State::load(&*config.fs, Path::new("state.json")).unwrap();
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+    execute_bisync(&plan, &config, &mut state);
+
+    // Deleting 1 file from the source deletes 1 of the 5 paths in the destination, 20% of it.
+    config.fs.remove_file(Path::new("src/Music/a.flac")).unwrap();
+    let plan = plan_bisync(&matcher, &config, &state).unwrap();
+
+    config.max_delete_percent = Some(25.0);
+    assert!(check_bisync(&plan, &matcher, &config).is_ok());
+    config.max_delete_percent = Some(10.0);
+    assert!(check_bisync(&plan, &matcher, &config).is_err());
+}
@@ -0,0 +1,240 @@
+//! snapshot.rs, part of Skarn.
+//!
+//! This module implements snapshot backups, in which each sync produces a new dated snapshot
+//! of the source directory within the destination directory, similar to rsync's `--link-dest`.
+//!
+//! The source directory is compared against the previous snapshot, using the configured
+//! comparison method. Files that are unchanged are hard-linked from the previous snapshot,
+//! so that each snapshot is a full copy of the tree but only changed files take up space.
+//!
+//! A snapshot that was interrupted is still used as the previous snapshot for the next sync,
+//! which copies whatever it is missing.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+
+use time;
+
+use vfs::FileSystem;
+use plan::Plan;
+use config::Config;
+use config::SymlinkMode::Follow;
+use dated::{unused_dated_name, list_dated, Retention};
+use path::{StringComponents, key_to_path, is_dir, walk_parallel};
+use execute::{Outcome, dest_path};
+use execute::Action::Link;
+use symlink::moved_link_target;
+
+pub struct Snapshot {
+    /// The directory containing the dated snapshots.
+    pub root: PathBuf,
+    /// The name of the snapshot created by this sync.
+    pub name: String,
+    /// The most recent existing snapshot, if there is one.
+    pub previous: Option<PathBuf>,
+    pub retention: Retention
+}
+
+impl Snapshot {
+    /// Start a new snapshot within `root`, named after the current time.
+    ///
+    /// An existing snapshot is never reused, even if it was created in the same second.
+    pub fn new(fs: &FileSystem, root: PathBuf, retention: Retention) -> io::Result<Snapshot> {
+        let name = unused_dated_name(fs, &root, time::now_utc());
+        Snapshot::named(fs, root, name, retention)
+    }
+
+    /// Start a new snapshot with the given name, after the most recent existing snapshot.
+    pub fn named(fs: &FileSystem, root: PathBuf, name: String, retention: Retention)
+    -> io::Result<Snapshot> {
+        let dir = root.join(&name);
+        let previous = try!(list_dated(fs, &root)).into_iter()
+            .map(|(_, path)| path)
+            .filter(|path| *path != dir)
+            .last();
+
+        Ok(Snapshot {
+            root: root,
+            name: name,
+            previous: previous,
+            retention: retention
+        })
+    }
+
+    /// The directory of the snapshot created by this sync.
+    pub fn dir(&self) -> PathBuf {
+        self.root.join(&self.name)
+    }
+
+    /// Remove old snapshots, according to the retention policy.
+    ///
+    /// The snapshot created by this sync is never removed.
+    pub fn prune(&self, fs: &FileSystem) -> io::Result<Vec<PathBuf>> {
+        self.retention.prune(fs, &self.root, &self.dir(), time::get_time())
+    }
+}
+
+/// Create a new snapshot, and fill it with hard links to the unchanged files of the previous
+/// snapshot.
+///
+/// Paths that the plan would delete or copy are left out. Directories that are still directories
+/// in the source are recreated, so that empty directories carry over, unless the plan deletes
+/// them along with their contents. Symlinks are copied, as they take up no more space than a
+/// hard link.
+pub fn link_unchanged(plan: &Plan, snapshot: &Snapshot, config: &Config) -> io::Result<Vec<Outcome>> {
+    let fs = &*config.fs;
+    let dir = snapshot.dir();
+    try!(fs.create_dir_all(&dir));
+
+    let previous = match snapshot.previous {
+        Some(ref previous) => previous,
+        None => return Ok(vec![])
+    };
+
+    let copied: HashSet<PathBuf> = plan.copy_paths.keys().map(|key| {
+        dest_path(&key_to_path(&key[..]), config)
+    }).collect();
+
+    let mut outcomes = vec![];
+
+    for path in try!(walk_parallel(fs, previous, false, config.threads)) {
+        let relative_path = path.relative_from(previous).unwrap().to_path_buf();
+        let metadata = try!(fs.symlink_metadata(&path));
+        let deleted = plan.delete_paths.get(&relative_path.string_components()[..]).is_some();
+
+        if metadata.is_dir() {
+            let follow_links = config.symlink_mode == Follow;
+            if is_dir(fs, &config.source_dir.join(&relative_path), follow_links) &&
+               (!deleted || try!(fs.read_dir(&path)).is_empty()) {
+                let result = fs.create_dir_all(&dir.join(&relative_path));
+                outcomes.push(Outcome { action: Link, path: relative_path, result: result });
+            }
+            continue;
+        }
+
+        if copied.contains(&relative_path) || deleted {
+            continue;
+        }
+
+        let result = link_file(&path, &dir.join(&relative_path), metadata.is_symlink(), snapshot,
+                               config);
+        outcomes.push(Outcome { action: Link, path: relative_path, result: result });
+    }
+
+    Ok(outcomes)
+}
+
+/// Link a file from the previous snapshot into the new one. Rewritten symlinks pointing within
+/// the previous snapshot are pointed at the new one instead.
+fn link_file(previous: &Path, path: &Path, is_symlink: bool, snapshot: &Snapshot, config: &Config)
+-> io::Result<()> {
+    let fs = &*config.fs;
+    if let Some(parent) = path.parent() {
+        try!(fs.create_dir_all(parent));
+    }
+
+    match snapshot.previous {
+        Some(ref previous_dir) if is_symlink => {
+            let target = try!(moved_link_target(previous, previous_dir, &snapshot.dir(), config));
+            fs.symlink(&target, path)
+        },
+        _ => fs.hard_link(previous, path)
+    }
+}
+
+// Tests
+
+#[test]
+fn test_snapshot() {
+    use std::io::Read;
+    use vfs::{MemoryFs, FileSystem};
+    use config::test_config;
+    use parser::parse_include_file;
+    use plan::plan;
+    use execute::execute;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.add_file("src/Music/b.flac", "new");
+    fs.add_file("snapshots/2015-06-21-143000/Music/a.flac", "a");
+    fs.add_file("snapshots/2015-06-21-143000/Music/b.flac", "old");
+    fs.add_file("snapshots/2015-06-21-143000/Music/c.flac", "c");
+    fs.add_file("snapshots/2015-06-20-143000/Music/a.flac", "a");
+    fs.create_dir_all(Path::new("src/Music/Empty")).unwrap();
+    fs.create_dir_all(Path::new("snapshots/2015-06-21-143000/Music/Empty")).unwrap();
+    fs.create_dir_all(Path::new("snapshots/2015-06-21-143000/Music/Removed")).unwrap();
+
+    let snapshot = Snapshot::named(
+        &fs, PathBuf::from("snapshots"), "2015-06-22-143000".to_string(), Retention::default()
+    ).unwrap();
+    assert_eq!(snapshot.previous, Some(PathBuf::from("snapshots/2015-06-21-143000")));
+
+    let mut config = test_config(fs);
+    config.dest_dir = snapshot.dir();
+    config.snapshot = Some(snapshot);
+
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+    let plan = plan(&matcher, &config).unwrap();
+    for outcome in execute(&plan, &config) {
+        assert!(outcome.result.is_ok());
+    }
+
+    let fs = &*config.fs;
+    let read = |path: &str| {
+        let mut contents = String::new();
+        fs.open(Path::new(path)).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    };
+    let ino = |path: &str| fs.metadata(Path::new(path)).unwrap().ino;
+
+    assert_eq!(ino("snapshots/2015-06-22-143000/Music/a.flac"),
+               ino("snapshots/2015-06-21-143000/Music/a.flac"));
+    assert_eq!(read("snapshots/2015-06-22-143000/Music/b.flac"), "new");
+    assert!(fs.metadata(Path::new("snapshots/2015-06-22-143000/Music/c.flac")).is_err());
+
+    // Empty directories are carried over while they are still in the source.
+    assert!(fs.metadata(Path::new("snapshots/2015-06-22-143000/Music/Empty")).unwrap().is_dir());
+    assert!(fs.metadata(Path::new("snapshots/2015-06-22-143000/Music/Removed")).is_err());
+
+    // The previous snapshot is untouched.
+    assert_eq!(read("snapshots/2015-06-21-143000/Music/b.flac"), "old");
+    assert_eq!(read("snapshots/2015-06-21-143000/Music/c.flac"), "c");
+}
+
+#[test]
+fn test_snapshot_rewritten_links() {
+    use vfs::MemoryFs;
+    use config::test_config;
+    use config::SymlinkMode::Rewrite;
+    use parser::parse_include_file;
+    use plan::plan;
+    use execute::execute;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.add_symlink("src/Music/link", "/src/Music/a.flac");
+    fs.add_file("snapshots/2015-06-21-143000/Music/a.flac", "a");
+    fs.add_symlink("snapshots/2015-06-21-143000/Music/link",
+                   "/snapshots/2015-06-21-143000/Music/a.flac");
+
+    let snapshot = Snapshot::named(
+        &fs, PathBuf::from("snapshots"), "2015-06-22-143000".to_string(), Retention::default()
+    ).unwrap();
+
+    let mut config = test_config(fs);
+    config.symlink_mode = Rewrite;
+    config.dest_dir = snapshot.dir();
+    config.snapshot = Some(snapshot);
+
+    // The link is compared against the previous snapshot, where it points into that snapshot.
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+    let plan = plan(&matcher, &config).unwrap();
+    assert!(plan.copies().is_empty());
+
+    for outcome in execute(&plan, &config) {
+        assert!(outcome.result.is_ok());
+    }
+    assert_eq!(config.fs.read_link(Path::new("snapshots/2015-06-22-143000/Music/link")).unwrap(),
+               PathBuf::from("/snapshots/2015-06-22-143000/Music/a.flac"));
+}
@@ -0,0 +1,118 @@
+//! symlink.rs, part of Skarn.
+//!
+//! This module implements the symlink policies described by `config::SymlinkMode`.
+//! Links may be copied verbatim, rewritten, or followed.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use vfs::FileSystem;
+use config::Config;
+use config::SymlinkMode::*;
+use path::{is_dir, is_symlink};
+
+/// Compute the target that a copy of the given source link should point to, when copied into
+/// `dest_root`.
+///
+/// When rewriting, the target and the source and destination directories are compared in
+/// canonical form, so that `..` components and symlinks in any of them don't hide a target
+/// inside the source directory.
+pub fn link_target(source_link: &Path, dest_root: &Path, config: &Config) -> io::Result<PathBuf> {
+    moved_link_target(source_link, &config.source_dir, dest_root, config)
+}
+
+/// Compute the target that a copy of a link within `from_root` should point to, when copied into
+/// `to_root`.
+///
+/// Rewritten links that point inside `from_root` are pointed at the same path inside `to_root`,
+/// like when carrying links over from one snapshot to the next.
+pub fn moved_link_target(link: &Path, from_root: &Path, to_root: &Path, config: &Config)
+-> io::Result<PathBuf> {
+    let fs = &*config.fs;
+    let target = try!(fs.read_link(link));
+
+    if config.symlink_mode != Rewrite || !target.is_absolute() {
+        return Ok(target);
+    }
+
+    let from_root = try!(fs.canonicalize(from_root));
+    let to_root = try!(fs.canonicalize(to_root));
+
+    match canonical_target(fs, &target).relative_from(&from_root) {
+        Some(relative_target) => Ok(to_root.join(relative_target)),
+        None => Ok(target.clone())
+    }
+}
+
+/// Canonicalize the directory containing an absolute link target.
+///
+/// The last component is kept as it is, so that a target which is itself a link is still
+/// pointed to, rather than the file it leads to. Targets in missing directories are left alone.
+fn canonical_target(fs: &FileSystem, target: &Path) -> PathBuf {
+    match (target.parent(), target.file_name()) {
+        (Some(parent), Some(name)) => match fs.canonicalize(parent) {
+            Ok(parent) => parent.join(name),
+            Err(_) => target.to_path_buf()
+        },
+        _ => target.to_path_buf()
+    }
+}
+
+/// Compare a source path and a destination path using link semantics.
+///
+/// The two are the same only if both are links, and the destination link points to the
+/// target that a fresh copy of the source link would have. The destination path is within the
+/// directory being compared against, which is the previous snapshot when taking a snapshot.
+pub fn same_link(source: &Path, dest: &Path, config: &Config) -> io::Result<bool> {
+    if !is_symlink(&*config.fs, source) || !is_symlink(&*config.fs, dest) {
+        return Ok(false);
+    }
+    let expected_target = try!(link_target(source, config.compare_dir(), config));
+    let dest_target = try!(config.fs.read_link(dest));
+    Ok(expected_target == dest_target)
+}
+
+/// Create a copy of the source link at the destination, replacing any existing file.
+pub fn copy_link(source: &Path, dest: &Path, config: &Config) -> io::Result<()> {
+    let target = try!(link_target(source, &config.dest_dir, config));
+    let fs = &*config.fs;
+
+    if fs.symlink_metadata(dest).is_ok() {
+        if is_dir(fs, dest, false) {
+            try!(fs.remove_dir(dest));
+        } else {
+            try!(fs.remove_file(dest));
+        }
+    }
+
+    fs.symlink(&target, dest)
+}
+
+// Tests
+
+#[test]
+fn test_rewrite_targets() {
+    use vfs::MemoryFs;
+    use config::test_config;
+
+    let fs = MemoryFs::new();
+    fs.add_file("data/src/Albums/01.flac", "album");
+    fs.add_symlink("src", "data/src");
+    fs.add_symlink("data/src/Music/inside", "/data/src/Albums/01.flac");
+    fs.add_symlink("data/src/Music/dotted", "/data/other/../src/Albums/01.flac");
+    fs.add_symlink("data/src/Music/outside", "/data/other/02.flac");
+    fs.add_file("data/other/02.flac", "other");
+    fs.add_file("dst/Music/placeholder", "");
+
+    // The source directory is reached through both a `..` component and a symlink.
+    let mut config = test_config(fs);
+    config.source_dir = PathBuf::from("dst/../src");
+    config.symlink_mode = Rewrite;
+
+    let target = |name: &str| {
+        link_target(&Path::new("src/Music").join(name), &config.dest_dir, &config).unwrap()
+    };
+    assert_eq!(target("inside"), PathBuf::from("/dst/Albums/01.flac"));
+    assert_eq!(target("dotted"), PathBuf::from("/dst/Albums/01.flac"));
+    assert_eq!(target("outside"), PathBuf::from("/data/other/02.flac"));
+}
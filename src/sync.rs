@@ -3,86 +3,340 @@
 //! This file contains the selective file sync algorithm described in `design/Algorithm.md`.
 
 use std::io;
-use std::fs::{PathExt, walk_dir};
+use std::path::Path;
+use std::collections::HashMap;
 
 use sequence_trie::SequenceTrie;
 
-use matcher::{Matcher, PathTrie};
-use matcher::Class::Included;
-use config::Config;
+use matcher::Matcher;
+use matcher::Class::*;
+use config::{Config, DeleteBehaviour};
+use config::SymlinkMode::Follow;
 use config::DeleteBehaviour::*;
+use self::CopyReason::*;
 use compare::ComparisonMethod;
-use path::StringComponents;
+use path::{StringComponents, is_dir, is_symlink, key_to_path, walk_parallel};
+use symlink::same_link;
+use transform::{transform_for, up_to_date};
 
-pub fn sync(matcher: &Matcher, config: &Config) -> io::Result<(PathTrie, PathTrie)> {
+/// The reason an included file is marked for copying.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum CopyReason {
+    /// The file has no equivalent in the destination directory.
+    New,
+    /// The destination's copy of the file differs from the source's.
+    Differs
+}
+
+/// A trie of paths relative to the source directory, marked for copying.
+pub type CopyTrie = SequenceTrie<String, CopyReason>;
+
+/// A trie of paths relative to the destination directory, marked for deletion.
+pub type DeleteTrie = SequenceTrie<String, DeleteBehaviour>;
+
+pub fn sync(matcher: &Matcher, config: &Config) -> io::Result<(CopyTrie, DeleteTrie)> {
     let source_dir = &config.source_dir;
-    let dest_dir = &config.dest_dir;
+    let dest_dir = config.compare_dir();
+    let fs = &*config.fs;
 
     // Classify every file in the source directory.
     // Included files are initially marked for copying, and filtered upon traversal of the dest dir.
-    let (mut copy_paths, _) = try!(matcher.classify_recursive(source_dir, config.include_by_default));
+    let follow_links = config.symlink_mode == Follow;
+    let (include_paths, _) = try!(matcher.classify_recursive(
+        fs, source_dir, config.include_by_default, follow_links, config.threads
+    ));
+
+    // Files subject to a transform are stored in the destination under a different name,
+    // so map each of these destination names back to the source file.
+    let mut transformed_names: HashMap<Vec<String>, Vec<String>> = HashMap::new();
+
+    // The paths are sorted, so that clashing transformed files are resolved consistently.
+    let mut include_keys: Vec<Vec<String>> = include_paths.keys()
+        .map(|key| key.into_iter().cloned().collect())
+        .collect();
+    include_keys.sort();
+
+    let mut copy_paths = SequenceTrie::new();
+    for path_key in include_keys {
+        let relative_path = key_to_path(&path_key[..]);
+
+        if let Some(transform) = transform_for(config, &relative_path) {
+            let dest_path = transform.dest_path(&relative_path);
+            let dest_key = dest_path.string_components();
+
+            // Source files copied to the same destination path would overwrite each other, so
+            // a file copied under its own name wins, followed by the first transformed file.
+            let untransformed_clash = dest_key != path_key &&
+                include_paths.get(&dest_key[..]).is_some() &&
+                transform_for(config, &dest_path).is_none();
+
+            if untransformed_clash || transformed_names.contains_key(&dest_key) {
+                warn!("Not transforming {}, as another source file is copied to {}",
+                      relative_path.display(), dest_path.display());
+                continue;
+            }
+            transformed_names.insert(dest_key, path_key.clone());
+        }
+
+        copy_paths.insert(&path_key[..], New);
+    }
 
     let mut delete_paths = SequenceTrie::new();
 
     let delete_behaviour = &config.delete_behaviour;
-    let comparison_method = &config.comparison_method;
-
-    // Walk the destination directory.
-    let dest_dir_walk = try!(walk_dir(dest_dir));
-    for entry in dest_dir_walk {
-        let path = try!(entry).path();
 
+    // Walk the destination directory, which is treated as empty if it doesn't exist yet.
+    // Links in the destination are never followed, so that deletion can't escape it.
+    // The paths are sorted, so each directory is visited before its contents.
+    let dest_dir_walk = if fs.symlink_metadata(dest_dir).is_ok() {
+        try!(walk_parallel(fs, dest_dir, false, config.threads))
+    } else {
+        vec![]
+    };
+    for path in dest_dir_walk {
         // Create a relative path, and a path relative to the source directory.
         let relative_path = path.relative_from(dest_dir).unwrap();
         let source_equiv = source_dir.join(relative_path.clone());
 
         let path_key: Vec<String> = relative_path.string_components();
 
+        // Transformed files are up to date if they are newer than their source file.
+        if let Some(source_key) = transformed_names.get(&path_key) {
+            let source = source_dir.join(key_to_path(&source_key[..]));
+
+            if try!(up_to_date(fs, &source, &path)) {
+                debug!(" Transformed File Up To Date: {}", relative_path.display());
+                copy_paths.remove(&source_key[..]);
+            } else {
+                debug!(" Transformed File Outdated: {}", relative_path.display());
+                copy_paths.insert(&source_key[..], Differs);
+            }
+            continue;
+        }
+
         // Case 1: Included, Equiv.
         // If the files match, remove the file from the list of files in need of copying.
-        if copy_paths.get(&path_key[..]).is_some() {
-            let same_file = try!(comparison_method.same_file(&path, &source_equiv));
-
-            if same_file {
+        // Files that are transformed have no equivalent under their original name.
+        if copy_paths.get(&path_key[..]).is_some() && transform_for(config, &relative_path).is_none() {
+            if try!(files_match(config, &source_equiv, &path)) {
                 debug!(" Files Match: {}", relative_path.display());
                 copy_paths.remove(&path_key[..]);
             } else {
                 debug!(" Files Differ: {}", relative_path.display());
+                copy_paths.insert(&path_key[..], Differs);
             }
+            continue;
         }
 
-        // Opportunistic deletion.
-        // If configured to delete ALL types of extraneous files, there is no need for further checks.
-        else if delete_behaviour.len() == 3 {
-            delete_paths.insert(&path_key[..], ());
+        // Directories containing included files are never candidates for deletion.
+        if is_dir(fs, &path, false) && copy_paths.get_node(&path_key[..]).is_some() {
+            continue;
         }
 
-        // Case 2: Excluded, Equiv.
-        else if source_equiv.exists() {
-            if delete_behaviour.contains(&ExcludedEquiv) {
-                delete_paths.insert(&path_key[..], ());
-            }
-        }
+        // Every extraneous file is categorised, so that the plan can report why it is deleted.
+        let category = delete_category(matcher, config, &relative_path, is_dir(fs, &path, false));
 
-        // Opportunistic deletion.
-        // If configured to delete all files with no equivalent, delete away!
-        else if delete_behaviour.contains(&IncludedNoEquiv) &&
-                delete_behaviour.contains(&ExcludedNoEquiv) {
-            delete_paths.insert(&path_key[..], ());
+        if delete_behaviour.contains(&category) {
+            delete_paths.insert(&path_key[..], category);
         }
+    }
 
-        // Case 3: Included, No Equiv.
-        else if let Included = matcher.classify(&relative_path) {
-            if delete_behaviour.contains(&IncludedNoEquiv) {
-                delete_paths.insert(&path_key[..], ());
-            }
-        }
+    Ok((copy_paths, delete_paths))
+}
 
-        // Case 4: Excluded, No Equiv.
-        else if delete_behaviour.contains(&ExcludedNoEquiv) {
-            delete_paths.insert(&path_key[..], ());
-        }
+/// Compare a source file with its equivalent in the destination directory.
+///
+/// Links are compared by target unless links are followed, and other files using the
+/// configured comparison method.
+pub fn files_match(config: &Config, source: &Path, dest: &Path) -> io::Result<bool> {
+    let fs = &*config.fs;
+    if config.symlink_mode != Follow && (is_symlink(fs, dest) || is_symlink(fs, source)) {
+        same_link(source, dest, config)
+    } else {
+        config.comparison_method.same_file(fs, dest, source)
     }
+}
 
-    Ok((copy_paths, delete_paths))
+/// Categorise an extraneous path in the destination directory, which isn't the copy of an
+/// included file.
+///
+/// Paths that can't be classified are categorised as included or excluded according to
+/// `config.include_by_default`, as in the traversal of the source directory.
+pub fn delete_category(matcher: &Matcher, config: &Config, relative_path: &Path, is_dir: bool)
+-> DeleteBehaviour {
+    let source_equiv = config.source_dir.join(relative_path);
+    let source_equiv_exists = if config.symlink_mode == Follow {
+        config.fs.metadata(&source_equiv).is_ok()
+    } else {
+        config.fs.symlink_metadata(&source_equiv).is_ok()
+    };
+
+    let included = match matcher.classify(relative_path, is_dir) {
+        Included => true,
+        Excluded => false,
+        Both => config.include_by_default
+    };
+
+    // Case 2: Excluded, Equiv.
+    if source_equiv_exists {
+        ExcludedEquiv
+    }
+    // Case 3: Included, No Equiv.
+    else if included {
+        IncludedNoEquiv
+    }
+    // Case 4: Excluded, No Equiv.
+    else {
+        ExcludedNoEquiv
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+use vfs::MemoryFs;
+#[cfg(test)]
+use config::SymlinkMode;
+
+#[cfg(test)]
+fn test_sync(fs: MemoryFs, include_lines: &[&str], symlink_mode: SymlinkMode)
+-> (CopyTrie, DeleteTrie)
+{
+    use std::path::Path;
+    use parser::parse_include_file;
+    use config::test_config;
+
+    let matcher = parse_include_file(&include_lines.connect("\n"), Path::new("test.ska")).unwrap();
+    let mut config = test_config(fs);
+    config.symlink_mode = symlink_mode;
+    sync(&matcher, &config).unwrap()
+}
+
+#[cfg(test)]
+fn key(path: &str) -> Vec<String> {
+    path.split('/').map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn test_delete_categories() {
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/same.flac", "same");
+    fs.add_file("dst/Music/same.flac", "same");
+    fs.add_file("src/Music/changed.flac", "new version");
+    fs.add_file("dst/Music/changed.flac", "old version");
+    fs.add_file("src/Music/new.flac", "new");
+    fs.add_file("dst/Music/removed.flac", "removed");
+    fs.add_file("src/Videos/film.mkv", "film");
+    fs.add_file("dst/Videos/film.mkv", "film");
+    fs.add_file("dst/Videos/old.mkv", "old");
+
+    let (copy_paths, delete_paths) = test_sync(fs, &["Music", "/!/ Videos"], SymlinkMode::Copy);
+
+    // Included, with an equivalent.
+    assert_eq!(copy_paths.get(&key("Music/same.flac")[..]), None);
+    assert_eq!(copy_paths.get(&key("Music/changed.flac")[..]), Some(&Differs));
+    assert_eq!(copy_paths.get(&key("Music/new.flac")[..]), Some(&New));
+    assert_eq!(delete_paths.get(&key("Music/same.flac")[..]), None);
+    assert_eq!(delete_paths.get(&key("Music")[..]), None);
+
+    assert_eq!(delete_paths.get(&key("Videos/film.mkv")[..]), Some(&ExcludedEquiv));
+    assert_eq!(delete_paths.get(&key("Music/removed.flac")[..]), Some(&IncludedNoEquiv));
+    assert_eq!(delete_paths.get(&key("Videos/old.mkv")[..]), Some(&ExcludedNoEquiv));
+}
+
+#[test]
+fn test_symlink_edge_cases() {
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/song.flac", "song");
+    fs.add_symlink("src/Music/same", "song.flac");
+    fs.add_symlink("dst/Music/same", "song.flac");
+    fs.add_symlink("src/Music/retargeted", "song.flac");
+    fs.add_symlink("dst/Music/retargeted", "other.flac");
+    fs.add_symlink("src/Music/was_file", "song.flac");
+    fs.add_file("dst/Music/was_file", "song");
+    fs.add_symlink("src/Music/dangling", "missing.flac");
+    fs.add_symlink("dst/Music/stale", "missing.flac");
+    fs.add_file("src/Albums/01.flac", "album");
+    fs.add_symlink("src/Music/albums", "../Albums");
+
+    let (copy_paths, delete_paths) = test_sync(fs, &["Music", "/!/ Albums"], SymlinkMode::Copy);
+
+    assert_eq!(copy_paths.get(&key("Music/same")[..]), None);
+    assert_eq!(copy_paths.get(&key("Music/retargeted")[..]), Some(&Differs));
+    // A file is replaced by a link, even if the link's target has the same contents.
+    assert_eq!(copy_paths.get(&key("Music/was_file")[..]), Some(&Differs));
+    assert_eq!(copy_paths.get(&key("Music/dangling")[..]), Some(&New));
+    assert_eq!(delete_paths.get(&key("Music/stale")[..]), Some(&IncludedNoEquiv));
+    // Links to directories are copied as links, rather than descended into.
+    assert_eq!(copy_paths.get(&key("Music/albums")[..]), Some(&New));
+    assert_eq!(copy_paths.get(&key("Music/albums/01.flac")[..]), None);
+}
+
+#[test]
+fn test_followed_symlinks() {
+    let fs = MemoryFs::new();
+    fs.add_file("src/Albums/01.flac", "album");
+    fs.add_symlink("src/Music/albums", "../Albums");
+    fs.add_symlink("src/Music/dangling", "missing.flac");
+    fs.add_symlink("src/Music/loop", "..");
+    fs.add_file("dst/Music/albums/01.flac", "album");
+
+    let (copy_paths, delete_paths) = test_sync(fs, &["Music"], SymlinkMode::Follow);
+
+    // A link to a directory containing it isn't followed.
+    assert!(copy_paths.get_node(&key("Music/loop")[..]).is_none());
+
+    assert_eq!(copy_paths.get(&key("Music/albums")[..]), None);
+    assert_eq!(copy_paths.get(&key("Music/albums/01.flac")[..]), None);
+    assert_eq!(delete_paths.get(&key("Music/albums/01.flac")[..]), None);
+}
+
+#[test]
+fn test_unclassified_deletions() {
+    use std::path::Path;
+    use parser::parse_include_file;
+    use config::test_config;
+
+    // Paths outside both tries are unclassifiable, and are categorised by the tie-breaker.
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+    let cases = [(true, IncludedNoEquiv), (false, ExcludedNoEquiv)];
+    for &(include_by_default, category) in cases.iter() {
+        let fs = MemoryFs::new();
+        fs.add_file("dst/Other/a.txt", "a");
+        let mut config = test_config(fs);
+        config.include_by_default = include_by_default;
+
+        let (_, delete_paths) = sync(&matcher, &config).unwrap();
+        assert_eq!(delete_paths.get(&key("Other/a.txt")[..]), Some(&category));
+    }
+}
+
+#[test]
+fn test_transform_clashes() {
+    use std::path::Path;
+    use parser::parse_include_file;
+    use config::test_config;
+    use transform::Transform;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/song.flac", "flac");
+    fs.add_file("src/Music/song.ogg", "ogg");
+    fs.add_file("src/Music/other.flac", "flac");
+    fs.add_file("src/Music/other.wav", "wav");
+
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+    let mut config = test_config(fs);
+    config.transforms = vec![
+        Transform::from_str("*.flac:ogg:oggenc -o {out} {in}").unwrap(),
+        Transform::from_str("*.wav:ogg:oggenc -o {out} {in}").unwrap()
+    ];
+    let (copy_paths, _) = sync(&matcher, &config).unwrap();
+
+    // A file with the transformed name is copied instead of the transformed file.
+    assert_eq!(copy_paths.get(&key("Music/song.ogg")[..]), Some(&New));
+    assert_eq!(copy_paths.get(&key("Music/song.flac")[..]), None);
+
+    // Of two transformed files, only the first is copied.
+    assert_eq!(copy_paths.get(&key("Music/other.flac")[..]), Some(&New));
+    assert_eq!(copy_paths.get(&key("Music/other.wav")[..]), None);
 }
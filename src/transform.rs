@@ -0,0 +1,163 @@
+//! transform.rs, part of Skarn.
+//!
+//! This module allows files to be transformed by a shell command as they are copied,
+//! like transcoding songs to a lower bitrate during music player sync.
+//!
+//! Transform rules are written `<pattern>:<extension>:<command>`, for example:
+//!
+//! ```text
+//! *.flac:ogg:oggenc -q 4 -o {out} {in}
+//! ```
+//!
+//! The pattern is a glob matched against paths relative to the source directory. Matching files
+//! are stored in the destination with their extension replaced, and are produced by running the
+//! command with `{in}` and `{out}` replaced by the (shell quoted) source and destination paths.
+//!
+//! A file is only transformed if no other file in the source is copied to the same destination
+//! path, such as `song.ogg` next to `song.flac`.
+
+use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use glob;
+
+use error::Error;
+use config::Config;
+use config::SymlinkMode::Follow;
+use path::is_symlink;
+use vfs::FileSystem;
+
+pub struct Transform {
+    pub pattern: glob::Pattern,
+    pub extension: String,
+    pub command: String
+}
+
+impl Transform {
+    pub fn from_str(rule: &str) -> Result<Transform, Error> {
+        let parts: Vec<&str> = rule.splitn(3, ':').collect();
+
+        if parts.len() != 3 || parts.iter().any(|p| p.is_empty()) {
+            return Err(
+                Error::new("error parsing transform rule")
+                .with_detail(format!("expected '<pattern>:<extension>:<command>', got: '{}'", rule))
+            );
+        }
+
+        let pattern = try!(glob::Pattern::new(parts[0]).map_err(|e| {
+            Error::new("error parsing transform rule")
+            .with_detail(format!("invalid pattern '{}': {}", parts[0], e.msg))
+        }));
+
+        Ok(Transform {
+            pattern: pattern,
+            extension: parts[1].to_string(),
+            command: parts[2].to_string()
+        })
+    }
+
+    /// Check whether a path relative to the source directory is subject to this transform.
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        self.pattern.matches_path(relative_path)
+    }
+
+    /// The path of the transformed file, relative to the destination directory.
+    pub fn dest_path(&self, relative_path: &Path) -> PathBuf {
+        relative_path.with_extension(&self.extension)
+    }
+
+    /// Produce the destination file by running the command on the source file.
+    ///
+    /// If the command fails, any partially written destination file is removed.
+    pub fn run(&self, source: &Path, dest: &Path) -> io::Result<()> {
+        let command = fill_template(&self.command, &shell_quote(source), &shell_quote(dest));
+
+        debug!("Running transform: {}", command);
+
+        let status = try!(Command::new("sh").arg("-c").arg(&command).status());
+
+        if !status.success() {
+            let _ = fs::remove_file(dest);
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("transform command exited with {}: {}", status, command)
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Find the first transform that applies to a path relative to the source directory.
+///
+/// Symlinks are only transformed if they are being followed.
+pub fn transform_for<'a>(config: &'a Config, relative_path: &Path) -> Option<&'a Transform> {
+    let source = config.source_dir.join(relative_path);
+    if config.symlink_mode != Follow && is_symlink(&*config.fs, &source) {
+        return None;
+    }
+    config.transforms.iter().find(|t| t.matches(relative_path))
+}
+
+/// Check whether a transformed file is up to date, which is the case if it is newer than the
+/// source file it was produced from.
+pub fn up_to_date(fs: &FileSystem, source: &Path, dest: &Path) -> io::Result<bool> {
+    let source_metadata = try!(fs.metadata(source));
+    let dest_metadata = try!(fs.metadata(dest));
+
+    Ok((dest_metadata.mtime, dest_metadata.mtime_nsec) >=
+       (source_metadata.mtime, source_metadata.mtime_nsec))
+}
+
+/// Replace `{in}` and `{out}` in a command template in a single pass, so that placeholders
+/// within the inserted paths are left alone.
+fn fill_template(template: &str, input: &str, output: &str) -> String {
+    let mut command = String::new();
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        if rest.starts_with("{in}") {
+            command.push_str(input);
+            rest = &rest[4..];
+        } else if rest.starts_with("{out}") {
+            command.push_str(output);
+            rest = &rest[5..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            command.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    command
+}
+
+/// Quote a path for use as a single argument in a shell command.
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace("'", "'\\''"))
+}
+
+// Tests
+
+#[test]
+fn test_transform_rule_parsing() {
+    let transform = Transform::from_str("*.flac:ogg:oggenc -o {out} {in}").unwrap();
+    assert!(transform.matches(Path::new("Artist/Album/01 Song.flac")));
+    assert!(!transform.matches(Path::new("Artist/Album/cover.jpg")));
+    assert_eq!(transform.dest_path(Path::new("Artist/01 Song.flac")), PathBuf::from("Artist/01 Song.ogg"));
+    assert_eq!(&transform.command[..], "oggenc -o {out} {in}");
+
+    assert!(Transform::from_str("*.flac:ogg").is_err());
+    assert!(Transform::from_str("*.flac::cp {in} {out}").is_err());
+}
+
+#[test]
+fn test_fill_template() {
+    let input = shell_quote(Path::new("{out}'; rm -rf ~; '.flac"));
+    let output = shell_quote(Path::new("out/{in}.ogg"));
+    assert_eq!(
+        fill_template("oggenc -o {out} {in}", &input, &output),
+        format!("oggenc -o {} {}", output, input)
+    );
+    assert_eq!(fill_template("cp {in} {out} # Ünïcode", "a", "b"), "cp a b # Ünïcode");
+}
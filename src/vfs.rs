@@ -0,0 +1,714 @@
+//! vfs.rs, part of Skarn.
+//!
+//! This module abstracts over the file system, so that syncs can be planned against an
+//! in-memory file system in tests.
+//!
+//! The `RealFs` type uses the operating system's file system, while `MemoryFs` keeps a tree of
+//! files, directories and symlinks in memory. Symlinks in a `MemoryFs` are resolved like those
+//! on disk, so relative, absolute, dangling and cyclic links all behave as they would for real.
+//!
+//! Everything Skarn does to files goes through a `FileSystem`, apart from transform commands,
+//! which are external programs working on the operating system's file system.
+
+use std::io;
+use std::io::{Read, Cursor};
+use std::fs;
+use std::os::unix;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::ffi::OsStrExt;
+use std::ffi::CString;
+
+use libc;
+use filetime::{self, FileTime};
+use std::path::{Path, PathBuf, Component};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use self::FileType::*;
+
+/// The maximum number of symlinks followed while resolving a path, as on Linux.
+const MAX_LINKS: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    /// Anything else, like a device or named pipe.
+    Other
+}
+
+/// File metadata, in a form that any file system can provide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub len: u64,
+    /// Modification time, in seconds since the Unix epoch.
+    pub mtime: i64,
+    /// The nanosecond part of the modification time.
+    pub mtime_nsec: i64,
+    /// Access time, in seconds since the Unix epoch.
+    pub atime: i64,
+    pub atime_nsec: i64,
+    pub ino: u64,
+    /// The device containing the file.
+    pub dev: u64,
+    /// Permission bits.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.file_type == File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == Symlink
+    }
+}
+
+/// The file system operations used to plan and carry out a sync.
+///
+/// Operations follow the semantics of their counterparts in `std::fs`.
+pub trait FileSystem: Send + Sync {
+    /// Get the metadata of a path, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Get the metadata of a path, without following a symlink at the end of the path.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// List the paths of the entries in a directory, in no particular order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Get the absolute form of a path, with every symlink and `.` or `..` component resolved.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    fn open(&self, path: &Path) -> io::Result<Box<Read>>;
+
+    /// Create or replace a file with the given contents.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Copy the contents of one file to another, returning the number of bytes copied.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Create a symlink at `path`, pointing to `target`.
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    /// Create a hard link at `path` to the file at `target`.
+    fn hard_link(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove an empty directory.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Set the permission bits of a path, following symlinks.
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// Set the owner and group of a path, without following symlinks.
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()>;
+
+    /// Set the access and modification times of a path, following symlinks.
+    fn set_times(&self, path: &Path, atime: FileTime, mtime: FileTime) -> io::Result<()>;
+}
+
+/// The operating system's file system.
+#[derive(Clone, Copy, Debug)]
+pub struct RealFs;
+
+impl RealFs {
+    fn convert(metadata: fs::Metadata) -> Metadata {
+        let file_type = metadata.file_type();
+        let file_type = if file_type.is_symlink() {
+            Symlink
+        } else if file_type.is_dir() {
+            Dir
+        } else if file_type.is_file() {
+            File
+        } else {
+            Other
+        };
+
+        Metadata {
+            file_type: file_type,
+            len: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            atime: metadata.atime(),
+            atime_nsec: metadata.atime_nsec(),
+            ino: metadata.ino(),
+            dev: metadata.dev(),
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid()
+        }
+    }
+}
+
+impl FileSystem for RealFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(RealFs::convert)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(RealFs::convert)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for entry in try!(fs::read_dir(path)) {
+            paths.push(try!(entry).path());
+        }
+        Ok(paths)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<Read>> {
+        let file = try!(fs::File::open(path));
+        Ok(box file as Box<Read>)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+        let mut file = try!(fs::File::create(path));
+        file.write_all(contents)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        fs::copy(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        unix::fs::symlink(target, path)
+    }
+
+    fn hard_link(&self, target: &Path, path: &Path) -> io::Result<()> {
+        fs::hard_link(target, path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        let c_path = try!(CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "path contains a null byte")
+        }));
+
+        let result = unsafe { libc::lchown(c_path.as_ptr(), uid, gid) };
+
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn set_times(&self, path: &Path, atime: FileTime, mtime: FileTime) -> io::Result<()> {
+        filetime::set_file_times(path, atime, mtime)
+    }
+}
+
+/// An in-memory file system, for testing.
+///
+/// Paths are treated lexically, with the current directory as the root: `/a` and `a` are the
+/// same path, and the root (or empty path) always exists as a directory. Each write advances a clock,
+/// which is used as the modification time of the file written.
+///
+/// Hard links share an inode number and modification time with their target, but not
+/// writes made after the link is created. Files are created with mode 644 and directories
+/// with mode 755, owned by root, and access times aren't tracked.
+pub struct MemoryFs {
+    state: Mutex<MemoryState>
+}
+
+struct MemoryState {
+    nodes: BTreeMap<PathBuf, Node>,
+    clock: i64,
+    next_ino: u64
+}
+
+#[derive(Clone)]
+struct Node {
+    kind: NodeKind,
+    mtime: i64,
+    ino: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32
+}
+
+#[derive(Clone)]
+enum NodeKind {
+    File(Vec<u8>),
+    Dir,
+    Link(PathBuf)
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs {
+            state: Mutex::new(MemoryState {
+                nodes: BTreeMap::new(),
+                clock: 1,
+                next_ino: 1
+            })
+        }
+    }
+
+    /// Add a file, creating its parent directories.
+    pub fn add_file(&self, path: &str, contents: &str) {
+        let path = Path::new(path);
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent).unwrap();
+        }
+        self.write(path, contents.as_bytes()).unwrap();
+    }
+
+    /// Add a symlink, creating its parent directories.
+    pub fn add_symlink(&self, path: &str, target: &str) {
+        let path = Path::new(path);
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent).unwrap();
+        }
+        self.symlink(Path::new(target), path).unwrap();
+    }
+
+    /// Set the modification time of a path, without following symlinks.
+    pub fn set_mtime(&self, path: &Path, mtime: i64) -> io::Result<()> {
+        self.state.lock().unwrap().update(path, false, |node| node.mtime = mtime)
+    }
+}
+
+impl MemoryState {
+    /// Resolve the symlinks in a path, optionally including a symlink at the end of the path.
+    fn resolve(&self, path: &Path, follow_last: bool) -> io::Result<PathBuf> {
+        self.resolve_links(path, follow_last, 0)
+    }
+
+    fn resolve_links(&self, path: &Path, follow_last: bool, num_links: usize) -> io::Result<PathBuf> {
+        if num_links > MAX_LINKS {
+            return Err(io::Error::new(io::ErrorKind::Other, "too many levels of symbolic links"));
+        }
+
+        let path = normalise(path);
+        let num_components = path.components().count();
+        let mut resolved = PathBuf::new();
+
+        for (i, component) in path.components().enumerate() {
+            resolved.push(component.as_os_str());
+
+            if i + 1 == num_components && !follow_last {
+                break;
+            }
+
+            let target = match self.nodes.get(&resolved) {
+                Some(&Node { kind: NodeKind::Link(ref target), .. }) => target.clone(),
+                _ => continue
+            };
+
+            // Relative targets are relative to the directory containing the link.
+            let link_dir = resolved.parent().map(|p| p.to_path_buf()).unwrap_or(PathBuf::new());
+            resolved = try!(self.resolve_links(&link_dir.join(target), true, num_links + 1));
+        }
+
+        Ok(resolved)
+    }
+
+    fn is_dir(&self, resolved: &Path) -> bool {
+        match self.nodes.get(resolved) {
+            Some(&Node { kind: NodeKind::Dir, .. }) => true,
+            Some(_) => false,
+            None => resolved.parent().is_none() || resolved.as_os_str().is_empty()
+        }
+    }
+
+    /// Check that the parent of a resolved path is a directory, so that it can be created.
+    fn check_parent(&self, resolved: &Path) -> io::Result<()> {
+        match resolved.parent() {
+            Some(parent) if !self.is_dir(parent) => Err(not_found(parent)),
+            _ => Ok(())
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, kind: NodeKind) {
+        let mode = match kind {
+            NodeKind::File(_) => 0o644,
+            NodeKind::Dir => 0o755,
+            NodeKind::Link(_) => 0o777
+        };
+        let node = Node {
+            kind: kind,
+            mtime: self.clock,
+            ino: self.next_ino,
+            mode: mode,
+            uid: 0,
+            gid: 0
+        };
+        self.clock += 1;
+        self.next_ino += 1;
+        self.nodes.insert(path, node);
+    }
+
+    fn metadata(&self, resolved: &Path) -> io::Result<Metadata> {
+        // The root directory isn't stored as a node.
+        let root = Node { kind: NodeKind::Dir, mtime: 0, ino: 0, mode: 0o755, uid: 0, gid: 0 };
+        let node = match self.nodes.get(resolved) {
+            Some(node) => node,
+            None if self.is_dir(resolved) => &root,
+            None => return Err(not_found(resolved))
+        };
+
+        let (file_type, len) = match node.kind {
+            NodeKind::File(ref contents) => (File, contents.len() as u64),
+            NodeKind::Dir => (Dir, 0),
+            NodeKind::Link(ref target) => (Symlink, target.as_os_str().len() as u64)
+        };
+
+        Ok(Metadata {
+            file_type: file_type,
+            len: len,
+            mtime: node.mtime,
+            mtime_nsec: 0,
+            atime: 0,
+            atime_nsec: 0,
+            ino: node.ino,
+            dev: 0,
+            mode: node.mode,
+            uid: node.uid,
+            gid: node.gid
+        })
+    }
+
+    /// Change the node at a path, following a symlink at the end of the path if requested.
+    fn update<F>(&mut self, path: &Path, follow_last: bool, f: F) -> io::Result<()>
+    where F: FnOnce(&mut Node) {
+        let resolved = try!(self.resolve(path, follow_last));
+        match self.nodes.get_mut(&resolved) {
+            Some(node) => {
+                f(node);
+                Ok(())
+            },
+            None => Err(not_found(&resolved))
+        }
+    }
+
+    fn contents(&self, resolved: &Path) -> io::Result<Vec<u8>> {
+        match self.nodes.get(resolved) {
+            Some(&Node { kind: NodeKind::File(ref contents), .. }) => Ok(contents.clone()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file")),
+            None => Err(not_found(resolved))
+        }
+    }
+
+    fn children(&self, resolved: &Path) -> Vec<PathBuf> {
+        self.nodes.keys()
+            .filter(|p| p.parent() == Some(resolved))
+            .cloned()
+            .collect()
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, true));
+        state.metadata(&resolved)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, false));
+        state.metadata(&resolved)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, true));
+
+        if !state.is_dir(&resolved) {
+            return Err(match state.nodes.get(&resolved) {
+                Some(_) => io::Error::new(io::ErrorKind::InvalidInput, "not a directory"),
+                None => not_found(&resolved)
+            });
+        }
+
+        // Entries are listed beneath the path given, rather than the resolved path.
+        Ok(state.children(&resolved).iter().map(|child| {
+            path.join(child.file_name().unwrap())
+        }).collect())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, false));
+        match state.nodes.get(&resolved) {
+            Some(&Node { kind: NodeKind::Link(ref target), .. }) => Ok(target.clone()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a symlink")),
+            None => Err(not_found(&resolved))
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, true));
+        try!(state.metadata(&resolved));
+        Ok(Path::new("/").join(resolved))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<Read>> {
+        let state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, true));
+        let contents = try!(state.contents(&resolved));
+        Ok(box Cursor::new(contents) as Box<Read>)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // Writing to a link writes to its target, as for a real file.
+        let resolved = try!(state.resolve(path, true));
+        try!(state.check_parent(&resolved));
+
+        if state.is_dir(&resolved) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "is a directory"));
+        }
+
+        state.insert(resolved, NodeKind::File(contents.to_vec()));
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let contents = {
+            let state = self.state.lock().unwrap();
+            let resolved = try!(state.resolve(from, true));
+            try!(state.contents(&resolved))
+        };
+        try!(self.write(to, &contents));
+        Ok(contents.len() as u64)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut current = PathBuf::new();
+
+        for component in path.components() {
+            current.push(component.as_os_str());
+            current = try!(state.resolve(&current, true));
+
+            if !state.nodes.contains_key(&current) && !state.is_dir(&current) {
+                state.insert(current.clone(), NodeKind::Dir);
+            } else if !state.is_dir(&current) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a directory"));
+            }
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, false));
+        try!(state.check_parent(&resolved));
+
+        if state.nodes.contains_key(&resolved) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+        }
+
+        state.insert(resolved, NodeKind::Link(target.to_path_buf()));
+        Ok(())
+    }
+
+    fn hard_link(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let target = try!(state.resolve(target, false));
+        let resolved = try!(state.resolve(path, false));
+        try!(state.check_parent(&resolved));
+
+        if state.nodes.contains_key(&resolved) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+        }
+
+        let node = match state.nodes.get(&target) {
+            Some(&Node { kind: NodeKind::Dir, .. }) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "is a directory"));
+            },
+            Some(node) => node.clone(),
+            None => return Err(not_found(&target))
+        };
+
+        state.nodes.insert(resolved, node);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let from = try!(state.resolve(from, false));
+        let to = try!(state.resolve(to, false));
+
+        if !state.nodes.contains_key(&from) {
+            return Err(not_found(&from));
+        }
+        try!(state.check_parent(&to));
+
+        // Move the node along with everything beneath it.
+        let moved: Vec<PathBuf> = state.nodes.keys().filter(|p| p.starts_with(&from)).cloned().collect();
+        for path in moved {
+            let node = state.nodes.remove(&path).unwrap();
+            let new_path = match path.relative_from(&from) {
+                Some(rest) if !rest.as_os_str().is_empty() => to.join(rest),
+                _ => to.clone()
+            };
+            state.nodes.insert(new_path, node);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, false));
+
+        match state.nodes.get(&resolved) {
+            Some(&Node { kind: NodeKind::Dir, .. }) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "is a directory"));
+            },
+            Some(_) => (),
+            None => return Err(not_found(&resolved))
+        }
+
+        state.nodes.remove(&resolved);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let resolved = try!(state.resolve(path, false));
+
+        match state.nodes.get(&resolved) {
+            Some(&Node { kind: NodeKind::Dir, .. }) => (),
+            Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory")),
+            None => return Err(not_found(&resolved))
+        }
+
+        if !state.children(&resolved).is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Other, "directory not empty"));
+        }
+
+        state.nodes.remove(&resolved);
+        Ok(())
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.state.lock().unwrap().update(path, true, |node| node.mode = mode)
+    }
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        self.state.lock().unwrap().update(path, false, |node| {
+            node.uid = uid;
+            node.gid = gid;
+        })
+    }
+
+    fn set_times(&self, path: &Path, _atime: FileTime, mtime: FileTime) -> io::Result<()> {
+        let mtime = mtime.seconds_relative_to_1970() as i64;
+        self.state.lock().unwrap().update(path, true, |node| node.mtime = mtime)
+    }
+}
+
+/// Remove `.` components and the root from a path, and resolve `..` components lexically.
+fn normalise(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => { result.pop(); },
+            c => result.push(c.as_os_str())
+        }
+    }
+    result
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no such file or directory: {}", path.display()))
+}
+
+// Tests
+
+#[test]
+fn test_memory_fs_symlinks() {
+    let fs = MemoryFs::new();
+    fs.add_file("music/album/01.flac", "song");
+    fs.add_symlink("links/relative", "../music/album");
+    fs.add_symlink("links/absolute", "/elsewhere");
+    fs.add_symlink("links/cycle", "cycle");
+
+    assert!(fs.metadata(Path::new("links/relative")).unwrap().is_dir());
+    assert!(fs.symlink_metadata(Path::new("links/relative")).unwrap().is_symlink());
+    assert_eq!(fs.read_dir(Path::new("links/relative")).unwrap(),
+               vec![PathBuf::from("links/relative/01.flac")]);
+    assert!(fs.metadata(Path::new("links/relative/01.flac")).unwrap().is_file());
+
+    assert!(fs.metadata(Path::new("links/absolute")).is_err());
+    assert!(fs.symlink_metadata(Path::new("links/absolute")).is_ok());
+    assert!(fs.metadata(Path::new("links/cycle")).is_err());
+
+    assert_eq!(fs.canonicalize(Path::new("links/relative/01.flac")).unwrap(),
+               PathBuf::from("/music/album/01.flac"));
+    assert_eq!(fs.canonicalize(Path::new("/music/../links")).unwrap(), PathBuf::from("/links"));
+    assert!(fs.canonicalize(Path::new("links/absolute")).is_err());
+}
+
+#[test]
+fn test_memory_fs_rename_and_remove() {
+    let fs = MemoryFs::new();
+    fs.add_file("a/b/c.txt", "c");
+    fs.rename(Path::new("a/b"), Path::new("a/d")).unwrap();
+
+    let mut contents = String::new();
+    fs.open(Path::new("a/d/c.txt")).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(&contents[..], "c");
+    assert!(fs.metadata(Path::new("a/b")).is_err());
+
+    assert!(fs.remove_dir(Path::new("a/d")).is_err());
+    fs.remove_file(Path::new("a/d/c.txt")).unwrap();
+    fs.remove_dir(Path::new("a/d")).unwrap();
+    assert_eq!(fs.read_dir(Path::new("a")).unwrap(), Vec::<PathBuf>::new());
+
+    fs.add_file("e.txt", "e");
+    fs.hard_link(Path::new("e.txt"), Path::new("a/e.txt")).unwrap();
+    let ino = fs.metadata(Path::new("e.txt")).unwrap().ino;
+    assert_eq!(fs.metadata(Path::new("a/e.txt")).unwrap().ino, ino);
+    assert!(fs.hard_link(Path::new("a"), Path::new("f")).is_err());
+}
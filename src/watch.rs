@@ -0,0 +1,404 @@
+//! watch.rs, part of Skarn.
+//!
+//! This module keeps a destination directory in sync by watching the source directory with
+//! inotify, rather than repeatedly walking the whole tree.
+//!
+//! Every directory beneath the source directory is watched. When files change, their paths are
+//! collected until no more events arrive for the debounce interval, so that bursts of writes
+//! are handled together. Only the changed paths (and the contents of changed directories) are
+//! then compared, copied and deleted.
+//!
+//! If the kernel's event queue overflows, or a batch of changes can't be planned, changes may
+//! have been missed, so the whole tree is synced instead. Changes to the targets of followed
+//! symlinks outside the source directory aren't noticed.
+
+use std::io;
+use std::cmp::max;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use libc;
+use time::SteadyTime;
+use inotify::INotify;
+use inotify::ffi::*;
+use sequence_trie::SequenceTrie;
+
+use matcher::Matcher;
+use matcher::Class::*;
+use config::Config;
+use config::SymlinkMode::Follow;
+use plan::{Plan, plan};
+use safety::check_plan;
+use sync::{files_match, delete_category};
+use sync::CopyReason::{self, New, Differs};
+use execute::{execute, dest_path, Outcome};
+use transform::{transform_for, up_to_date};
+use backup::Backup;
+use path::{StringComponents, is_dir, walk};
+
+const WATCH_MASK: u32 = IN_CLOSE_WRITE | IN_CREATE | IN_DELETE | IN_MOVED_FROM | IN_MOVED_TO |
+                        IN_ATTRIB;
+
+/// Watch the source directory, syncing changed paths until an error occurs.
+///
+/// The destination directory should already be in sync, as changes made before watching
+/// began aren't noticed. The outcomes of each sync are passed to `report`.
+///
+/// Errors while syncing a batch of changes are logged, and the whole tree is synced once the
+/// debounce interval has passed again, so that only errors reading events stop the watch.
+/// Each batch backs files up into a dated directory of its own.
+pub fn watch<F>(matcher: &Matcher, config: &mut Config, mut report: F) -> io::Result<()>
+where F: FnMut(Vec<Outcome>) {
+    let mut inotify = try!(INotify::init());
+    let mut watches = HashMap::new();
+    try!(add_watches(&mut inotify, &mut watches, config, Path::new("")));
+
+    let mut changed = BTreeSet::new();
+    let mut overflowed = false;
+    let mut last_event = SteadyTime::now();
+
+    loop {
+        let pending = !changed.is_empty() || overflowed;
+        let debounce = config.debounce as i64;
+
+        // Block until events arrive, or until the debounce interval has passed since the last.
+        let timeout = if pending {
+            max(debounce - (SteadyTime::now() - last_event).num_milliseconds(), 0)
+        } else {
+            -1
+        };
+
+        if try!(wait_readable(inotify.fd, timeout)) {
+            let events: Vec<_> = try!(inotify.wait_for_events()).iter().map(|e| {
+                (e.wd, e.mask, PathBuf::from(&e.name))
+            }).collect();
+            last_event = SteadyTime::now();
+
+            for (wd, mask, name) in events {
+                if mask & IN_Q_OVERFLOW != 0 {
+                    overflowed = true;
+                } else if mask & IN_IGNORED != 0 {
+                    watches.remove(&wd);
+                } else if let Some(dir) = watches.get(&wd) {
+                    changed.insert(dir.join(name));
+                }
+            }
+            continue;
+        }
+
+        // The wait may have been interrupted by a signal before the interval passed.
+        if !pending || (SteadyTime::now() - last_event).num_milliseconds() < debounce {
+            continue;
+        }
+
+        if let Some(backup) = config.backup.take() {
+            config.backup = Some(Backup::new(&*config.fs, backup.dir, backup.retention));
+        }
+
+        let result = plan_changes(matcher, config, &mut inotify, &mut watches, &changed, overflowed);
+        changed.clear();
+        overflowed = false;
+
+        let plan = match result {
+            Ok(plan) => plan,
+            Err(e) => {
+                warn!("Error planning changes, syncing everything after the next interval: {}", e);
+                overflowed = true;
+                last_event = SteadyTime::now();
+                continue;
+            }
+        };
+
+        if let Err(e) = config.comparison_method.finish() {
+            warn!("Error saving comparison state: {}", e);
+        }
+
+        if plan.is_empty() {
+            continue;
+        }
+
+        match check_plan(&plan, matcher, config) {
+            Ok(()) => report(execute(&plan, config)),
+            Err(e) => warn!("Skipping changes, as syncing them would be unsafe: {}", e)
+        }
+    }
+}
+
+/// Wait up to a timeout in milliseconds (or forever if negative) for a file descriptor to become
+/// readable, returning whether it did.
+fn wait_readable(fd: libc::c_int, timeout_ms: i64) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 };
+
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout_ms as libc::c_int) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(e) }
+        },
+        n => Ok(n > 0)
+    }
+}
+
+/// Plan a batch of changes, watching any new directories. If events were missed, the whole tree
+/// is planned instead.
+fn plan_changes(matcher: &Matcher, config: &Config, inotify: &mut INotify,
+                watches: &mut HashMap<i32, PathBuf>, changed: &BTreeSet<PathBuf>, overflowed: bool)
+-> io::Result<Plan> {
+    if overflowed {
+        info!("Changes may have been missed, syncing everything");
+        try!(add_watches(inotify, watches, config, Path::new("")));
+        return plan(matcher, config);
+    }
+
+    let fs = &*config.fs;
+    let follow_links = config.symlink_mode == Follow;
+    let paths = coalesce(changed);
+
+    // New directories need watching too, including any created before their watch.
+    for path in paths.iter() {
+        if is_dir(fs, &config.source_dir.join(path), follow_links) {
+            try!(add_watches(inotify, watches, config, path));
+        }
+    }
+    plan_paths(matcher, config, &paths)
+}
+
+/// Watch a directory within the source directory, and every directory beneath it.
+fn add_watches(inotify: &mut INotify, watches: &mut HashMap<i32, PathBuf>, config: &Config,
+               relative_path: &Path) -> io::Result<()> {
+    let fs = &*config.fs;
+    let follow_links = config.symlink_mode == Follow;
+    let root = config.source_dir.join(relative_path);
+
+    let mut dirs = vec![root.clone()];
+    dirs.extend(try!(walk(fs, &root, follow_links)).into_iter().filter(|path| {
+        is_dir(fs, path, follow_links)
+    }));
+
+    for dir in dirs {
+        let wd = try!(inotify.add_watch(&dir, WATCH_MASK));
+        watches.insert(wd, dir.relative_from(&config.source_dir).unwrap().to_path_buf());
+    }
+    Ok(())
+}
+
+/// Remove paths beneath other paths in the set, as syncing a directory covers its contents.
+fn coalesce(changed: &BTreeSet<PathBuf>) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = vec![];
+    for path in changed.iter() {
+        // Sorting places each directory directly before its contents.
+        match paths.last() {
+            Some(last) if path.starts_with(last) => continue,
+            _ => ()
+        }
+        paths.push(path.clone());
+    }
+    paths
+}
+
+/// Plan the changes needed to sync the given paths, relative to the source directory.
+///
+/// Directories are synced along with everything beneath them. Files are categorised for copying
+/// and deletion as in a full sync.
+pub fn plan_paths(matcher: &Matcher, config: &Config, paths: &[PathBuf]) -> io::Result<Plan> {
+    let mut copy_paths = SequenceTrie::new();
+    let mut delete_paths = SequenceTrie::new();
+
+    for relative_path in paths.iter() {
+        // The destination paths of the included source files, which are not to be deleted.
+        let mut produced = HashSet::new();
+
+        for source_path in try!(source_files(config, relative_path)) {
+            if !is_included(matcher, config, &source_path) {
+                continue;
+            }
+
+            produced.insert(dest_path(&source_path, config));
+            if try!(transform_clashes(matcher, config, &source_path)) {
+                continue;
+            }
+            if let Some(reason) = try!(copy_reason(config, &source_path)) {
+                copy_paths.insert(&source_path.string_components()[..], reason);
+            }
+        }
+
+        // A deleted source file may have a destination file under a different name.
+        let mut dest_paths = vec![relative_path.clone()];
+        if transform_for(config, relative_path).is_some() {
+            dest_paths.push(dest_path(relative_path, config));
+        }
+
+        for dest_root in dest_paths {
+            for dest_file in try!(dest_files(config, &dest_root)) {
+                let path_is_dir = is_dir(&*config.fs, &config.dest_dir.join(&dest_file), false);
+
+                // Directories containing included files are never candidates for deletion.
+                if produced.contains(&dest_file) ||
+                    (path_is_dir && produced.iter().any(|p| p.starts_with(&dest_file))) {
+                    continue;
+                }
+
+                let category = delete_category(matcher, config, &dest_file, path_is_dir);
+                if config.delete_behaviour.contains(&category) {
+                    delete_paths.insert(&dest_file.string_components()[..], category);
+                }
+            }
+        }
+    }
+
+    Ok(Plan {
+        moves: vec![],
+        copy_paths: copy_paths,
+        delete_paths: delete_paths
+    })
+}
+
+/// Classify a source file, using the tie-breaker for files that can't be classified.
+fn is_included(matcher: &Matcher, config: &Config, relative_path: &Path) -> bool {
+    match matcher.classify(relative_path, false) {
+        Included => true,
+        Excluded => false,
+        Both => config.include_by_default
+    }
+}
+
+/// Check whether a transformed source file clashes with another included source file copied to
+/// the same destination path, in which case it isn't copied, as in a full sync.
+fn transform_clashes(matcher: &Matcher, config: &Config, relative_path: &Path) -> io::Result<bool> {
+    let fs = &*config.fs;
+    let dest = match transform_for(config, relative_path) {
+        Some(transform) => transform.dest_path(relative_path),
+        None => return Ok(false)
+    };
+
+    // Files copied under their own name take precedence over transformed files.
+    if dest != relative_path && fs.symlink_metadata(&config.source_dir.join(&dest)).is_ok() &&
+       is_included(matcher, config, &dest) && transform_for(config, &dest).is_none() {
+        return Ok(true);
+    }
+
+    // Otherwise the first transformed file in order of path takes precedence.
+    let parent = config.source_dir.join(relative_path.parent().unwrap_or(Path::new("")));
+    for sibling in try!(fs.read_dir(&parent)) {
+        let sibling = sibling.relative_from(&config.source_dir).unwrap().to_path_buf();
+        if sibling.as_path() < relative_path && is_included(matcher, config, &sibling) &&
+           transform_for(config, &sibling).map(|t| t.dest_path(&sibling)) == Some(dest.clone()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// List a source path and everything beneath it, excluding directories.
+fn source_files(config: &Config, relative_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let fs = &*config.fs;
+    let follow_links = config.symlink_mode == Follow;
+    let path = config.source_dir.join(relative_path);
+
+    let paths = if is_dir(fs, &path, follow_links) {
+        let contents = try!(walk(fs, &path, follow_links));
+        contents.into_iter().filter(|p| !is_dir(fs, p, follow_links)).collect()
+    } else if fs.symlink_metadata(&path).is_ok() {
+        vec![path]
+    } else {
+        vec![]
+    };
+
+    Ok(paths.iter().map(|p| p.relative_from(&config.source_dir).unwrap().to_path_buf()).collect())
+}
+
+/// List a destination path and everything beneath it, without following links.
+fn dest_files(config: &Config, relative_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let fs = &*config.fs;
+    let path = config.dest_dir.join(relative_path);
+
+    if fs.symlink_metadata(&path).is_err() {
+        return Ok(vec![]);
+    }
+
+    let mut paths = vec![path.clone()];
+    if is_dir(fs, &path, false) {
+        paths.extend(try!(walk(fs, &path, false)).into_iter());
+    }
+
+    Ok(paths.iter().map(|p| p.relative_from(&config.dest_dir).unwrap().to_path_buf()).collect())
+}
+
+/// Compare an included source file with its copy, returning the reason it needs copying, if any.
+fn copy_reason(config: &Config, relative_path: &Path) -> io::Result<Option<CopyReason>> {
+    let fs = &*config.fs;
+    let source = config.source_dir.join(relative_path);
+    let dest = config.dest_dir.join(dest_path(relative_path, config));
+
+    if fs.symlink_metadata(&dest).is_err() {
+        return Ok(Some(New));
+    }
+
+    let up_to_date = if transform_for(config, relative_path).is_some() {
+        try!(up_to_date(fs, &source, &dest))
+    } else {
+        try!(files_match(config, &source, &dest))
+    };
+
+    Ok(if up_to_date { None } else { Some(Differs) })
+}
+
+// Tests
+
+#[test]
+fn test_plan_paths() {
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/a.flac", "a");
+    fs.add_file("src/Music/b.flac", "b");
+    fs.add_file("src/Music/New/c.flac", "c");
+    fs.add_file("src/Music/New/d.flac", "d");
+    fs.add_file("src/Music/New/desktop.ini", "");
+    fs.add_file("dst/Music/b.flac", "b");
+    fs.add_file("dst/Music/New/d.flac", "d");
+    fs.add_file("dst/Music/old.flac", "old");
+    fs.add_file("dst/Music/Old/e.flac", "e");
+
+    let config = test_config(fs);
+    let matcher = parse_include_file("Music\n/!/ Music/New/desktop.ini", Path::new("test.ska")).unwrap();
+
+    let changed: BTreeSet<PathBuf> = ["Music/a.flac", "Music/b.flac", "Music/New",
+        "Music/New/c.flac", "Music/old.flac", "Music/Old"].iter().map(|p| PathBuf::from(*p)).collect();
+    let paths = coalesce(&changed);
+    assert_eq!(paths.len(), 5);
+
+    let plan = plan_paths(&matcher, &config, &paths).unwrap();
+    let key = |path: &str| -> Vec<String> { path.split('/').map(|s| s.to_string()).collect() };
+
+    assert_eq!(plan.copies().len(), 2);
+    assert_eq!(plan.copy_paths.get(&key("Music/a.flac")[..]), Some(&New));
+    assert_eq!(plan.copy_paths.get(&key("Music/New/c.flac")[..]), Some(&New));
+
+    assert_eq!(plan.deletions().len(), 3);
+    for path in ["Music/old.flac", "Music/Old", "Music/Old/e.flac"].iter() {
+        assert!(plan.delete_paths.get(&key(path)[..]).is_some());
+    }
+}
+
+#[test]
+fn test_plan_paths_transform_clashes() {
+    use vfs::MemoryFs;
+    use config::test_config;
+    use parser::parse_include_file;
+    use transform::Transform;
+
+    let fs = MemoryFs::new();
+    fs.add_file("src/Music/song.flac", "flac");
+    fs.add_file("src/Music/song.ogg", "ogg");
+    fs.add_file("dst/Music/song.ogg", "ogg");
+
+    let mut config = test_config(fs);
+    config.transforms = vec![Transform::from_str("*.flac:ogg:oggenc -o {out} {in}").unwrap()];
+    let matcher = parse_include_file("Music", Path::new("test.ska")).unwrap();
+
+    // The file copied under its own name is neither overwritten nor deleted.
+    let plan = plan_paths(&matcher, &config, &[PathBuf::from("Music/song.flac")]).unwrap();
+    assert!(plan.is_empty());
+}